@@ -0,0 +1,711 @@
+use std::fmt;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use hound::{WavReader, WavSpec, WavWriter};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::{SAMPLE_RATE, dsp};
+
+/// The sample format audio is written with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SampleFormat {
+    /// 8-bit integer PCM
+    Int8,
+    /// 16-bit integer PCM
+    Int16,
+    /// 24-bit integer PCM
+    Int24,
+    /// 32-bit float
+    Float32,
+}
+
+impl SampleFormat {
+    fn bits(&self) -> u16 {
+        match self {
+            SampleFormat::Int8 => 8,
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Float32 => 32,
+        }
+    }
+}
+
+/// Which channels the signal is written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ChannelLayout {
+    /// A single channel
+    Mono,
+    /// Stereo, with the signal on the left channel and silence on the right
+    Left,
+    /// Stereo, with the signal on the right channel and silence on the left
+    Right,
+    /// Stereo, with the signal on both channels
+    Both,
+}
+
+impl ChannelLayout {
+    pub(crate) fn channels(&self) -> u16 {
+        match self {
+            ChannelLayout::Mono => 1,
+            _ => 2,
+        }
+    }
+
+    /// Split a sample into the values written to each channel
+    pub(crate) fn frame(&self, sample: f32) -> [Option<f32>; 2] {
+        match self {
+            ChannelLayout::Mono => [Some(sample), None],
+            ChannelLayout::Left => [Some(sample), Some(0.)],
+            ChannelLayout::Right => [Some(0.), Some(sample)],
+            ChannelLayout::Both => [Some(sample), Some(sample)],
+        }
+    }
+}
+
+/// Options controlling how encoded audio is written
+#[derive(Clone, Debug)]
+pub struct OutputOptions {
+    /// Peak level of the signal in dBFS, 0 being full scale
+    pub level_dbfs: f64,
+    /// DC offset added to every sample written or played, as a fraction of full scale.
+    /// Anything it pushes past full scale is clamped
+    pub dc_offset: f64,
+    pub format: SampleFormat,
+    pub layout: ChannelLayout,
+}
+
+impl OutputOptions {
+    /// The gain applied to samples from `Signal::to_samples` to reach the peak level
+    pub fn gain(&self) -> f32 {
+        10_f64.powf(self.level_dbfs / 20.) as f32
+    }
+
+    /// Add the DC offset to a value about to be written, clamping it to full scale
+    pub(crate) fn offset(&self, value: f32) -> f32 {
+        (value + self.dc_offset as f32).clamp(-1., 1.)
+    }
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            level_dbfs: 0.,
+            dc_offset: 0.,
+            format: SampleFormat::Float32,
+            layout: ChannelLayout::Mono,
+        }
+    }
+}
+
+/// Write samples as a WAV file to `writer`, in the format described by `options`.
+pub fn write_wav<W: Write + Seek>(
+    writer: W,
+    samples: &[f32],
+    options: &OutputOptions,
+) -> Result<(), hound::Error> {
+    let spec = WavSpec {
+        channels: options.layout.channels(),
+        sample_rate: SAMPLE_RATE as u32,
+        bits_per_sample: options.format.bits(),
+        sample_format: match options.format {
+            SampleFormat::Float32 => hound::SampleFormat::Float,
+            _ => hound::SampleFormat::Int,
+        },
+    };
+
+    let gain = options.gain();
+    // Largest value of an integer sample
+    let full_scale = ((1_i64 << (options.format.bits() - 1)) - 1) as f32;
+
+    let mut writer = WavWriter::new(writer, spec)?;
+
+    for sample in samples {
+        for value in options.layout.frame(*sample * gain).into_iter().flatten() {
+            let value = options.offset(value);
+            match options.format {
+                SampleFormat::Float32 => writer.write_sample(value)?,
+                _ => writer.write_sample((value * full_scale).round() as i32)?,
+            }
+        }
+    }
+
+    writer.finalize()
+}
+
+/// Write samples to a WAV file at `path`, see `write_wav`.
+pub fn write_wav_file<P: AsRef<Path>>(
+    path: P,
+    samples: &[f32],
+    options: &OutputOptions,
+) -> Result<(), hound::Error> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_wav(file, samples, options)
+}
+
+/// An error while loading audio
+#[derive(Debug)]
+pub enum AudioError {
+    /// The file couldn't be opened
+    Io(std::io::Error),
+    /// The WAV file couldn't be read
+    Wav(hound::Error),
+    /// The compressed audio file couldn't be decoded
+    Decode(SymphoniaError),
+    /// The file has no audio track
+    NoTrack,
+    /// The audio claims to have no channels
+    NoChannels,
+    /// The selected channel doesn't exist, the second field being the channel count
+    Channel(u16, u16),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Io(err) => write!(f, "failed to open audio: {err}"),
+            AudioError::Wav(err) => write!(f, "failed to read WAV: {err}"),
+            AudioError::Decode(err) => write!(f, "failed to decode audio: {err}"),
+            AudioError::NoTrack => write!(f, "no audio track found"),
+            AudioError::NoChannels => write!(f, "the audio has no channels"),
+            AudioError::Channel(channel, channels) => {
+                write!(f, "no channel {channel}, the audio has {channels} channels")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+impl From<std::io::Error> for AudioError {
+    fn from(err: std::io::Error) -> Self {
+        AudioError::Io(err)
+    }
+}
+
+impl From<hound::Error> for AudioError {
+    fn from(err: hound::Error) -> Self {
+        AudioError::Wav(err)
+    }
+}
+
+impl From<SymphoniaError> for AudioError {
+    fn from(err: SymphoniaError) -> Self {
+        AudioError::Decode(err)
+    }
+}
+
+/// Which channel of multichannel audio gets decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ChannelSelect {
+    /// Average every channel together
+    #[default]
+    Mix,
+    /// Use a single channel, counting from 0
+    Channel(u16),
+}
+
+impl ChannelSelect {
+    /// Turn interleaved samples with `channels` channels into a single channel
+    pub fn apply(&self, samples: &[f32], channels: u16) -> Result<Vec<f32>, AudioError> {
+        if channels == 0 {
+            return Err(AudioError::NoChannels);
+        }
+        let frames = samples.chunks_exact(channels as usize);
+
+        match *self {
+            ChannelSelect::Mix => Ok(frames
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                .collect()),
+            ChannelSelect::Channel(channel) if channel < channels => {
+                Ok(frames.map(|frame| frame[channel as usize]).collect())
+            }
+            ChannelSelect::Channel(channel) => Err(AudioError::Channel(channel, channels)),
+        }
+    }
+}
+
+/// Load WAV audio from `reader` as mono samples between -1 and 1 at `SAMPLE_RATE`,
+/// ready to be decoded.
+///
+/// 8, 16, 24 and 32-bit integer and 32-bit float files are supported, and get
+/// resampled if they're at a different sample rate.
+pub fn load_wav<R: Read>(reader: R, channel: ChannelSelect) -> Result<Vec<f32>, AudioError> {
+    let reader = WavReader::new(reader)?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            // Largest magnitude of an integer sample
+            let full_scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / full_scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let samples = channel.apply(&samples, spec.channels)?;
+
+    Ok(dsp::resample(
+        &samples,
+        spec.sample_rate,
+        SAMPLE_RATE as u32,
+    ))
+}
+
+/// Load a WAV file at `path`, see `load_wav`.
+pub fn load_wav_file<P: AsRef<Path>>(
+    path: P,
+    channel: ChannelSelect,
+) -> Result<Vec<f32>, AudioError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    load_wav(file, channel)
+}
+
+/// Load compressed audio - FLAC, Ogg Vorbis or MP3 - from `source` as mono samples between
+/// -1 and 1 at `SAMPLE_RATE`, ready to be decoded.
+///
+/// The format is detected from the content, with `extension` as a hint if known.
+pub fn load_compressed(
+    source: Box<dyn MediaSource>,
+    extension: Option<&str>,
+    channel: ChannelSelect,
+) -> Result<Vec<f32>, AudioError> {
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+
+    let stream = MediaSourceStream::new(source, Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format.default_track().ok_or(AudioError::NoTrack)?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(SAMPLE_RATE as u32);
+    let mut channels = track.codec_params.channels.map_or(1, |c| c.count() as u16);
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // The end of the stream shows up as an unexpected EOF
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Skip over corrupt packets rather than giving up on the whole file
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        channels = spec.channels.count() as u16;
+
+        let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buf.samples());
+    }
+
+    let samples = channel.apply(&samples, channels)?;
+
+    Ok(dsp::resample(&samples, sample_rate, SAMPLE_RATE as u32))
+}
+
+/// Load an audio file at `path` of any supported format - WAV, FLAC, Ogg Vorbis or MP3.
+///
+/// WAV files are recognised by their RIFF header and loaded with `load_wav`, everything
+/// else goes through `load_compressed`.
+pub fn load_file<P: AsRef<Path>>(path: P, channel: ChannelSelect) -> Result<Vec<f32>, AudioError> {
+    let path = path.as_ref();
+    let mut file = std::fs::File::open(path)?;
+
+    let mut magic = [0; 12];
+    let is_wav =
+        file.read_exact(&mut magic).is_ok() && &magic[0..4] == b"RIFF" && &magic[8..12] == b"WAVE";
+    file.rewind()?;
+
+    if is_wav {
+        load_wav(std::io::BufReader::new(file), channel)
+    } else {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        load_compressed(Box::new(file), extension, channel)
+    }
+}
+
+/// The format of headerless raw PCM audio, as used in Unix pipelines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum RawFormat {
+    /// Signed 16-bit little endian integers
+    S16le,
+    /// 32-bit little endian floats
+    F32le,
+}
+
+impl RawFormat {
+    /// Size of a single sample in bytes
+    pub fn sample_size(&self) -> usize {
+        match self {
+            RawFormat::S16le => 2,
+            RawFormat::F32le => 4,
+        }
+    }
+
+    /// Convert a sample from its raw bytes to a float between -1 and 1
+    fn read_sample(&self, bytes: &[u8]) -> f32 {
+        match self {
+            RawFormat::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.,
+            RawFormat::F32le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+
+    /// Append the raw bytes of a sample to `out`
+    fn write_sample(&self, sample: f32, out: &mut Vec<u8>) {
+        match self {
+            RawFormat::S16le => out.extend_from_slice(
+                &((sample.clamp(-1., 1.) * i16::MAX as f32).round() as i16).to_le_bytes(),
+            ),
+            RawFormat::F32le => out.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+
+/// Reads mono raw PCM audio in chunks, resampling it to `SAMPLE_RATE`.
+pub struct RawReader<R: Read> {
+    reader: R,
+    format: RawFormat,
+    resampler: dsp::Resampler,
+    /// Bytes of a sample split across two reads
+    leftover: Vec<u8>,
+}
+
+impl<R: Read> RawReader<R> {
+    /// Create a reader of audio in `format` at `sample_rate` Hz
+    pub fn new(reader: R, format: RawFormat, sample_rate: u32) -> RawReader<R> {
+        RawReader {
+            reader,
+            format,
+            resampler: dsp::Resampler::new(sample_rate, SAMPLE_RATE as u32),
+            leftover: Vec::new(),
+        }
+    }
+
+    /// Read the next chunk of up to `max_bytes` bytes, returning None once the reader has ended.
+    pub fn read_chunk(&mut self, max_bytes: usize) -> std::io::Result<Option<Vec<f32>>> {
+        let mut buf = std::mem::take(&mut self.leftover);
+        let start = buf.len();
+        buf.resize(start + max_bytes, 0);
+
+        let read = loop {
+            match self.reader.read(&mut buf[start..]) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        buf.truncate(start + read);
+
+        let whole = buf.len() - buf.len() % self.format.sample_size();
+        self.leftover = buf.split_off(whole);
+
+        let samples: Vec<f32> = buf
+            .chunks_exact(self.format.sample_size())
+            .map(|bytes| self.format.read_sample(bytes))
+            .collect();
+
+        Ok(Some(self.resampler.process(&samples)))
+    }
+}
+
+/// Write samples as raw PCM audio in `format` at `sample_rate` Hz, with the level, DC
+/// offset and channel layout from `options`. Stereo layouts are written interleaved.
+pub fn write_raw<W: Write>(
+    mut writer: W,
+    samples: &[f32],
+    format: RawFormat,
+    sample_rate: u32,
+    options: &OutputOptions,
+) -> std::io::Result<()> {
+    let gain = options.gain();
+    let samples = dsp::resample(samples, SAMPLE_RATE as u32, sample_rate);
+
+    let mut buf = Vec::new();
+
+    // Write in chunks rather than building the whole signal in memory twice
+    for chunk in samples.chunks(4096) {
+        buf.clear();
+
+        for sample in chunk {
+            for value in options.layout.frame(*sample * gain).into_iter().flatten() {
+                format.write_sample(options.offset(value), &mut buf);
+            }
+        }
+
+        writer.write_all(&buf)?;
+    }
+
+    writer.flush()
+}
+
+/// Samples at or above this level are at full scale
+const CLIP_LEVEL: f32 = 0.999;
+
+/// Level measurements of some audio, to tell if the input is too quiet or clipping
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LevelMetrics {
+    /// RMS level, 1 being full scale
+    pub rms: f32,
+    /// Highest absolute sample value, 1 being full scale
+    pub peak: f32,
+    /// Number of samples stuck at full scale. A clean tone only touches full scale for
+    /// a single sample at a time, so only runs of them are counted
+    pub clipped: usize,
+}
+
+impl LevelMetrics {
+    /// Measure the level of samples between -1 and 1
+    pub fn measure(samples: &[f32]) -> LevelMetrics {
+        if samples.is_empty() {
+            return LevelMetrics::default();
+        }
+
+        let power = samples.iter().map(|s| (s * s) as f64).sum::<f64>() / samples.len() as f64;
+
+        // Whether two neighbouring samples are both stuck at the same end of full scale
+        let stuck = |a: f32, b: f32| a.abs() >= CLIP_LEVEL && b.abs() >= CLIP_LEVEL && a * b > 0.;
+        let clipped = (0..samples.len())
+            .filter(|&i| {
+                (i > 0 && stuck(samples[i - 1], samples[i]))
+                    || (i + 1 < samples.len() && stuck(samples[i], samples[i + 1]))
+            })
+            .count();
+
+        LevelMetrics {
+            rms: power.sqrt() as f32,
+            peak: samples.iter().fold(0., |peak: f32, s| peak.max(s.abs())),
+            clipped,
+        }
+    }
+
+    /// RMS level in dBFS
+    pub fn rms_dbfs(&self) -> f32 {
+        20. * self.rms.log10()
+    }
+
+    /// Peak level in dBFS
+    pub fn peak_dbfs(&self) -> f32 {
+        20. * self.peak.log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Write interleaved integer `frames` to a WAV in memory at `SAMPLE_RATE`
+    fn int_wav(bits: u16, channels: u16, frames: &[i32]) -> Cursor<Vec<u8>> {
+        let spec = WavSpec {
+            channels,
+            sample_rate: SAMPLE_RATE as u32,
+            bits_per_sample: bits,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut wav, spec).unwrap();
+        for &sample in frames {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        wav.set_position(0);
+        wav
+    }
+
+    #[test]
+    fn int_bit_depths() {
+        for bits in [8, 16, 24, 32] {
+            let full_scale = 1_i64 << (bits - 1);
+            let (max, min, half) = (
+                (full_scale - 1) as i32,
+                -full_scale as i32,
+                (full_scale / 2) as i32,
+            );
+
+            let samples =
+                load_wav(int_wav(bits, 1, &[max, min, half, 0]), ChannelSelect::Mix).unwrap();
+            assert_eq!(samples.len(), 4);
+            assert!(
+                samples.iter().all(|sample| (-1. ..=1.).contains(sample)),
+                "{bits}-bit"
+            );
+
+            // The most positive value is a step short of full scale
+            let step = 1. / full_scale as f32;
+            assert!(
+                (samples[0] - (1. - step)).abs() < 1e-6,
+                "{bits}-bit max {}",
+                samples[0]
+            );
+            assert_eq!(samples[1..], [-1., 0.5, 0.], "{bits}-bit");
+        }
+    }
+
+    #[test]
+    fn float() {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut wav, spec).unwrap();
+        for sample in [1., -1., 0.25] {
+            writer.write_sample(sample as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+        wav.set_position(0);
+
+        assert_eq!(load_wav(wav, ChannelSelect::Mix).unwrap(), [1., -1., 0.25]);
+    }
+
+    #[test]
+    fn pick_and_mix_channels() {
+        // Two 16-bit frames of left 0.5, right -0.25 then left 1, right 0
+        let frames = [16384, -8192, -32768, 0];
+
+        let mix = load_wav(int_wav(16, 2, &frames), ChannelSelect::Mix).unwrap();
+        assert_eq!(mix, [0.125, -0.5]);
+        let right = load_wav(int_wav(16, 2, &frames), ChannelSelect::Channel(1)).unwrap();
+        assert_eq!(right, [-0.25, 0.]);
+        assert!(matches!(
+            load_wav(int_wav(16, 2, &frames), ChannelSelect::Channel(2)),
+            Err(AudioError::Channel(2, 2))
+        ));
+    }
+
+    #[test]
+    fn no_channels() {
+        for channel in [ChannelSelect::Mix, ChannelSelect::Channel(0)] {
+            assert!(matches!(
+                channel.apply(&[0.5, 0.25], 0),
+                Err(AudioError::NoChannels)
+            ));
+        }
+    }
+
+    /// Hands out the bytes it holds 1 to 3 at a time, being interrupted before each read
+    struct Trickle {
+        bytes: Vec<u8>,
+        pos: usize,
+        interrupted: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+
+            let len = (self.pos % 3 + 1)
+                .min(buf.len())
+                .min(self.bytes.len() - self.pos);
+            buf[..len].copy_from_slice(&self.bytes[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    /// Read everything from `bytes` in `format` through a `Trickle`
+    fn read_raw(bytes: Vec<u8>, format: RawFormat) -> Vec<f32> {
+        let trickle = Trickle {
+            bytes,
+            pos: 0,
+            interrupted: false,
+        };
+        let mut reader = RawReader::new(trickle, format, SAMPLE_RATE as u32);
+
+        let mut samples = Vec::new();
+        while let Some(chunk) = reader.read_chunk(8192).unwrap() {
+            samples.extend(chunk);
+        }
+        samples
+    }
+
+    #[test]
+    fn raw_samples_split_across_reads() {
+        let sent = [0.5, -1., 0.25, 0.];
+
+        let mut bytes = Vec::new();
+        for sample in sent {
+            bytes.extend_from_slice(&((sample * 32768.) as i16).to_le_bytes());
+        }
+        assert_eq!(read_raw(bytes, RawFormat::S16le), sent);
+
+        let bytes = sent
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(read_raw(bytes, RawFormat::F32le), sent);
+    }
+
+    #[test]
+    fn raw_odd_byte_count() {
+        // The half a sample left at the end is dropped rather than read as a whole one
+        let bytes = vec![0x00, 0x40, 0x00, 0xc0, 0x12];
+        assert_eq!(read_raw(bytes, RawFormat::S16le), [0.5, -0.5]);
+    }
+
+    #[test]
+    fn clipped_runs() {
+        // Runs of 3 and 2 count every sample, while a lone full scale sample and a jump
+        // between the two ends of full scale don't count
+        let samples = [0.5, 1., 1., 1., 0.2, -1., 0.3, -1., -1., 0., 1., -1.];
+        assert_eq!(LevelMetrics::measure(&samples).clipped, 5);
+    }
+}
@@ -2,7 +2,13 @@ use std::f64::consts::PI;
 
 use image::DynamicImage;
 
-use crate::SAMPLE_RATE;
+use crate::{
+    SAMPLE_RATE,
+    dsp::{self, Demodulator, DemodulatorKind, Pll},
+    fax480::{self, FAX480},
+    martinm1::{self, MartinM1},
+    trace::{TraceEvent, VisBit},
+};
 
 /// A frequency component struct, consists of a frequency and duration.
 /// A SSTV signal is made up of a single-tone, frequency modulated to encode the image,
@@ -17,6 +23,12 @@ pub struct Signal {
     inner: Vec<Component>,
 }
 
+impl Default for Signal {
+    fn default() -> Self {
+        Signal::new()
+    }
+}
+
 impl Signal {
     pub fn new() -> Signal {
         Signal { inner: Vec::new() }
@@ -25,16 +37,92 @@ impl Signal {
     /// Get the time-domain samples as opposed to our hybrid
     /// time and frequency domain data.
     ///
-    /// This is what gets written to the WAV file.
+    /// This is what gets written to the WAV file. Components with a frequency
+    /// of 0 are silent.
     pub fn to_samples(&self) -> Vec<f32> {
-        let mut samples = Vec::new();
+        self.to_samples_shaped(&ToneShaping::default())
+    }
+
+    /// Get the time-domain samples, shaping the tone to keep the signal's bandwidth down.
+    ///
+    /// See `ToneShaping` for details.
+    pub fn to_samples_shaped(&self, shaping: &ToneShaping) -> Vec<f32> {
+        if us_to_n_samples(shaping.ramp_us) == 0 && us_to_n_samples(shaping.transition_us) < 2 {
+            return self.to_samples_unshaped();
+        }
+
+        // Instantaneous frequency of every sample, None when silent
+        let mut freqs: Vec<Option<f64>> = Vec::new();
+        // Time since the start of the signal, kept exact so that components shorter than
+        // a sample don't drift the timing of everything after them
+        let mut time_us: f64 = 0.;
+
+        for component in self.inner.iter() {
+            let start = us_to_n_samples(time_us);
+            time_us += component.len_us;
+            let total_length = us_to_n_samples(time_us) - start;
+            let freq = Some(component.freq as f64).filter(|freq| *freq > 0.);
+            freqs.extend(std::iter::repeat_n(freq, total_length));
+        }
+
+        let amplitudes = ramp_amplitudes(&freqs, us_to_n_samples(shaping.ramp_us));
+
+        // Hold the last frequency through silences, so smoothing doesn't pull the
+        // edges of the tones down towards 0Hz
+        let mut held = freqs.iter().flatten().next().copied().unwrap_or(0.);
+        let freqs: Vec<f64> = freqs
+            .into_iter()
+            .map(|freq| {
+                held = freq.unwrap_or(held);
+                held
+            })
+            .collect();
+
+        let freqs = smooth_transitions(&freqs, us_to_n_samples(shaping.transition_us));
+
         let mut phase: f64 = 0.;
 
+        freqs
+            .iter()
+            .zip(amplitudes)
+            .map(|(freq, amplitude)| {
+                let sample = (phase.sin() * amplitude) as f32;
+                phase += 2. * PI * freq / SAMPLE_RATE as f64;
+                sample
+            })
+            .collect()
+    }
+
+    /// Get the time-domain samples without any shaping, in one pass over the components
+    /// rather than buffering the whole frequency track.
+    fn to_samples_unshaped(&self) -> Vec<f32> {
+        let len_us: f64 = self.inner.iter().map(|component| component.len_us).sum();
+        let mut samples = Vec::with_capacity(us_to_n_samples(len_us));
+
+        // Hold the last frequency through silences like the shaped path does, so the
+        // phase carries on the same way
+        let mut held = self
+            .inner
+            .iter()
+            .find(|component| component.freq > 0)
+            .map_or(0., |component| component.freq as f64);
+        let mut phase: f64 = 0.;
+        let mut time_us: f64 = 0.;
+
         for component in self.inner.iter() {
-            let total_length = ((component.len_us as f64 / 1000000.) * SAMPLE_RATE as f64) as usize;
+            let start = us_to_n_samples(time_us);
+            time_us += component.len_us;
+            let total_length = us_to_n_samples(time_us) - start;
+
+            let amplitude = if component.freq > 0 {
+                held = component.freq as f64;
+                1.
+            } else {
+                0.
+            };
             for _ in 0..total_length {
-                samples.push((phase.sin()) as f32);
-                phase += 2. * PI * component.freq as f64 / SAMPLE_RATE as f64;
+                samples.push((phase.sin() * amplitude) as f32);
+                phase += 2. * PI * held / SAMPLE_RATE as f64;
             }
         }
 
@@ -45,6 +133,306 @@ impl Signal {
     pub fn push(&mut self, freq: usize, len_us: f64) {
         self.inner.push(Component { freq, len_us });
     }
+
+    /// Add a scanline of luminance values to the signal, each value lasting `pixel_us`.
+    ///
+    /// Colour modes call this once per colour channel, greyscale modes once per line.
+    pub fn push_scanline(&mut self, values: impl IntoIterator<Item = u8>, pixel_us: f64) {
+        for value in values {
+            self.push(value_to_freq(value), pixel_us);
+        }
+    }
+
+    /// Add the calibration header followed by the VIS code of a mode.
+    ///
+    /// By default the header is a 1000Hz 200ms preamble, a 1900Hz 300ms leader tone, a 1200Hz
+    /// 10ms break and another leader, followed by the VIS code: a 30ms 1200Hz start bit,
+    /// 7 data bits sent LSB first and an even parity bit, each 30ms long - 1100Hz for a 1 and
+    /// 1300Hz for a 0 - and a 1200Hz stop bit.
+    ///
+    /// Everything apart from the VIS code's timing can be changed through `options`.
+    pub fn push_calibration_header(&mut self, mode: Mode, options: &EncoderOptions) {
+        if options.preamble_us > 0. {
+            self.push(1000, options.preamble_us);
+        }
+
+        if options.vox_tones {
+            for freq in VOX_TONES {
+                self.push(freq, VOX_TONE_US);
+            }
+        }
+
+        self.push(1900, options.leader_us);
+        self.push(1200, options.break_us);
+        self.push(1900, options.leader_us);
+
+        let vis = options.vis.unwrap_or(mode.vis_code());
+
+        // start bit
+        self.push(1200, 30_000.);
+
+        for bit in 0..7 {
+            self.push(vis_bit_freq(vis & (1 << bit) != 0), 30_000.);
+        }
+
+        // even parity bit, over the 7 data bits only
+        self.push(vis_bit_freq((vis & 0x7f).count_ones() % 2 == 1), 30_000.);
+
+        // stop bit
+        self.push(1200, 30_000.);
+    }
+}
+
+/// Tone shaping applied when generating samples, to keep the generated audio
+/// within a standard SSB passband.
+///
+/// Both kinds of shaping are off by default.
+#[derive(Clone, Debug, Default)]
+pub struct ToneShaping {
+    /// Length of the raised cosine smoothing applied to the frequency in μs (filtered FM),
+    /// 0 to switch between components instantly
+    pub transition_us: f64,
+    /// Length of the raised cosine amplitude ramp wherever the tone starts or stops in μs,
+    /// 0 to start and stop at full amplitude
+    pub ramp_us: f64,
+}
+
+/// Smooth a frequency track by convolving it with a raised cosine (Hann) window
+/// `len` samples long.
+fn smooth_transitions(freqs: &[f64], len: usize) -> Vec<f64> {
+    if len < 2 {
+        return freqs.to_vec();
+    }
+
+    let window: Vec<f64> = (0..len)
+        .map(|i| 0.5 - 0.5 * (2. * PI * (i as f64 + 0.5) / len as f64).cos())
+        .collect();
+    let total: f64 = window.iter().sum();
+    let half = len / 2;
+
+    (0..freqs.len())
+        .map(|i| {
+            window
+                .iter()
+                .enumerate()
+                .map(|(j, w)| {
+                    // Clamp to the ends of the track
+                    let at = (i + j).saturating_sub(half).min(freqs.len() - 1);
+                    freqs[at] * w
+                })
+                .sum::<f64>()
+                / total
+        })
+        .collect()
+}
+
+/// Build the amplitude envelope of a signal, 0 where it's silent and
+/// rising/falling along a raised cosine `len` samples long wherever the tone starts/stops.
+fn ramp_amplitudes(freqs: &[Option<f64>], len: usize) -> Vec<f64> {
+    // Distance in samples to the closest silence, looking forwards then backwards
+    let mut distances = vec![0; freqs.len()];
+
+    let mut since_silence = 0;
+    for (i, freq) in freqs.iter().enumerate() {
+        since_silence = if freq.is_some() { since_silence + 1 } else { 0 };
+        distances[i] = since_silence;
+    }
+
+    since_silence = 0;
+    for (i, freq) in freqs.iter().enumerate().rev() {
+        since_silence = if freq.is_some() { since_silence + 1 } else { 0 };
+        distances[i] = distances[i].min(since_silence);
+    }
+
+    distances
+        .into_iter()
+        .map(|distance| match distance {
+            0 => 0.,
+            distance if distance >= len => 1.,
+            distance => 0.5 - 0.5 * (PI * distance as f64 / len as f64).cos(),
+        })
+        .collect()
+}
+
+/// The MMSSTV VOX tone sequence, used to trigger VOX keyed transmitters and repeaters
+const VOX_TONES: [usize; 8] = [1900, 1500, 1900, 1500, 2300, 1500, 2300, 1500];
+/// Length of each VOX tone in μs
+const VOX_TONE_US: f64 = 100_000.;
+
+/// Options controlling how an image is encoded, primarily the calibration header
+/// sent before the image.
+#[derive(Clone, Debug)]
+pub struct EncoderOptions {
+    /// Length of the 1000Hz preamble tone in μs, 0 to leave it out
+    pub preamble_us: f64,
+    /// Send the MMSSTV VOX tone sequence before the leader tones
+    pub vox_tones: bool,
+    /// Length of each 1900Hz leader tone in μs
+    pub leader_us: f64,
+    /// Length of the 1200Hz break between the leader tones in μs
+    pub break_us: f64,
+    /// VIS code to send instead of the mode's own
+    pub vis: Option<u8>,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        EncoderOptions {
+            preamble_us: 200_000.,
+            vox_tones: false,
+            leader_us: 300_000.,
+            break_us: 10_000.,
+            vis: None,
+        }
+    }
+}
+
+/// Frequency of a single VIS bit
+fn vis_bit_freq(bit: bool) -> usize {
+    if bit { 1100 } else { 1300 }
+}
+
+/// Convert a luminance value to its modulating frequency, in a range between
+/// 1500Hz (black) and 2300Hz (white).
+pub fn value_to_freq(value: u8) -> usize {
+    let range = 2300. - 1500.;
+    (value as f64 / u8::MAX as f64 * range) as usize + 1500
+}
+
+/// Convert a frequency back into a luminance value, clamping anything outside
+/// of the 1500Hz to 2300Hz range.
+pub fn freq_to_value(freq: f64) -> u8 {
+    let brightness = (freq - 1500.) / (2300. - 1500.);
+    (brightness * 255.).round().clamp(0., 255.) as u8
+}
+
+/// Every mode supported by RSSTV.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Mode {
+    MartinM1,
+    Fax480,
+}
+
+impl Mode {
+    /// Every supported mode
+    pub const ALL: [Mode; 2] = [Mode::MartinM1, Mode::Fax480];
+
+    /// The VIS code sent in the calibration header of this mode
+    pub fn vis_code(&self) -> u8 {
+        match self {
+            Mode::MartinM1 => 44,
+            Mode::Fax480 => 85,
+        }
+    }
+
+    /// Look up a mode from a received VIS code
+    pub fn from_vis_code(vis: u8) -> Option<Mode> {
+        Mode::ALL.into_iter().find(|mode| mode.vis_code() == vis)
+    }
+
+    /// A fresh transcoder for this mode
+    pub fn transcoder(&self) -> Box<dyn SSTVMode> {
+        self.decoder(DecoderOptions::default())
+    }
+
+    /// A fresh transcoder for this mode, decoding with `options`
+    pub fn decoder(&self, options: DecoderOptions) -> Box<dyn SSTVMode> {
+        match self {
+            Mode::MartinM1 => Box::new(MartinM1::with_options(options)),
+            Mode::Fax480 => Box::new(FAX480::with_options(options)),
+        }
+    }
+
+    /// Length of a whole line in μs, from the start of one sync to the next
+    pub fn line_us(&self) -> f64 {
+        match self {
+            Mode::MartinM1 => martinm1::LINE_US,
+            Mode::Fax480 => fax480::LINE_US,
+        }
+    }
+
+    /// Work out which mode a frequency track from `dsp::demodulate` is sent in, from the VIS
+    /// code if the header is there and from how far apart the line syncs are if not
+    pub fn detect(track: &[f64]) -> Option<Mode> {
+        let mut out = DSPOut::new(track);
+        get_calibration_header(&mut out)
+            .and_then(|header| Mode::from_vis_code(header.vis))
+            .or_else(|| find_line_syncs(&DSPOut::new(track), &Mode::ALL).map(|sync| sync.mode))
+    }
+
+    /// Where the pixels are sent within each line
+    pub fn scans(&self) -> &'static [Scan] {
+        match self {
+            Mode::MartinM1 => &martinm1::SCANS,
+            Mode::Fax480 => &fax480::SCANS,
+        }
+    }
+}
+
+/// A run of pixels sent within a line, such as one colour channel
+#[derive(Clone, Copy, Debug)]
+pub struct Scan {
+    /// Time from the start of the line's sync to the first pixel in μs
+    pub start_us: f64,
+    /// Number of pixels
+    pub pixels: u32,
+    /// Length of each pixel in μs
+    pub pixel_us: f64,
+    /// The RGB channel the pixels are, or None for luminance
+    pub channel: Option<usize>,
+}
+
+/// Options changing how signals are decoded
+#[derive(Clone, Debug)]
+pub struct DecoderOptions {
+    /// How the frequency of the signal is measured
+    pub demodulator: DemodulatorKind,
+    /// Loop bandwidth in Hz when using the PLL demodulator
+    pub pll_bandwidth: f64,
+    /// Bring the input to a steady level before filtering, see `dsp::Agc`. Off by default
+    /// so decoding behaves as it always has, the CLI turns it on unless `--no-agc` is passed
+    pub agc: bool,
+    /// Blank out impulse noise before anything else, see `dsp::NoiseBlanker`
+    pub noise_blanker: bool,
+    /// Run adaptive noise reduction after the bandpass, see `dsp::NoiseReduction`
+    pub noise_reduction: bool,
+    /// Record what the decoder reads into `DecodeInfo::events`, see `trace`
+    pub trace: bool,
+    /// Start decoding from the line syncs when there's no header, for signals tuned into
+    /// partway through. See `find_line_syncs`.
+    ///
+    /// Nothing in a line says which row of the image it is, so the lines received are
+    /// filled in from the top whichever row they were sent as. An image joined partway
+    /// through comes out shifted up, with the rows that were missed left blank at the bottom
+    pub headerless: bool,
+}
+
+impl Default for DecoderOptions {
+    fn default() -> Self {
+        DecoderOptions {
+            demodulator: DemodulatorKind::default(),
+            pll_bandwidth: Pll::default().loop_bandwidth,
+            agc: false,
+            noise_blanker: false,
+            noise_reduction: false,
+            trace: false,
+            headerless: false,
+        }
+    }
+}
+
+impl DecoderOptions {
+    /// The demodulator to measure the signal's frequency with
+    pub fn demodulator(&self) -> Box<dyn Demodulator> {
+        match self.demodulator {
+            DemodulatorKind::Pll => Box::new(Pll {
+                loop_bandwidth: self.pll_bandwidth,
+                ..Pll::default()
+            }),
+            kind => kind.demodulator(),
+        }
+    }
 }
 
 /// The SSTVMode trait. This trait encompasses all the functions required to implement
@@ -52,14 +440,82 @@ impl Signal {
 ///
 /// TODO: Move general things from the Martin M1 encoder out and implement more modes.
 pub trait SSTVMode {
-    fn new() -> Self;
-    fn encode(&mut self, image: DynamicImage) -> Signal;
+    fn new() -> Self
+    where
+        Self: Sized,
+    {
+        Self::with_options(DecoderOptions::default())
+    }
+    fn with_options(options: DecoderOptions) -> Self
+    where
+        Self: Sized;
+    fn encode(&mut self, image: DynamicImage) -> Signal {
+        self.encode_with_options(image, &EncoderOptions::default())
+    }
+    fn encode_with_options(&mut self, image: DynamicImage, options: &EncoderOptions) -> Signal;
     fn decode(&mut self, _audio: &[f32]) -> DecodeResult {
         todo!()
     }
+    /// Decode like `decode`, reading from a `track` already demodulated rather than
+    /// demodulating again. `track` has to be `dsp::demodulate` of every sample given so
+    /// far, `audio` included, with the options this decoder was made with
+    fn decode_track(&mut self, audio: &[f32], track: &[f64]) -> DecodeResult;
     fn get_image(&self) -> DynamicImage;
 }
 
+/// Decodes audio arriving in chunks, detecting the mode from the header or the line syncs
+/// if it isn't known up front.
+///
+/// `Mode::detect` needs the whole signal, which isn't there when decoding live. Instead
+/// everything received is kept and checked for each mode's header and line syncs as it
+/// arrives, and decoding switches over to whichever mode's decoder starts first.
+pub struct DetectingDecoder {
+    options: DecoderOptions,
+    /// Everything received while the mode is still unknown
+    samples: Vec<f32>,
+    /// The mode and its decoder, once known
+    decoder: Option<(Mode, Box<dyn SSTVMode>)>,
+}
+
+impl DetectingDecoder {
+    /// A decoder for `mode`, or for whichever mode turns up if None
+    pub fn new(mode: Option<Mode>, options: DecoderOptions) -> DetectingDecoder {
+        DetectingDecoder {
+            decoder: mode.map(|mode| (mode, mode.decoder(options.clone()))),
+            options,
+            samples: Vec::new(),
+        }
+    }
+
+    /// The mode being decoded, once it's known
+    pub fn mode(&self) -> Option<Mode> {
+        self.decoder.as_ref().map(|(mode, _)| *mode)
+    }
+
+    /// Decode the next chunk of audio, see `SSTVMode::decode`
+    pub fn decode(&mut self, audio: &[f32]) -> DecodeResult {
+        if let Some((_, decoder)) = &mut self.decoder {
+            return decoder.decode(audio);
+        }
+
+        self.samples.extend_from_slice(audio);
+        let track = dsp::demodulate(&self.samples, &self.options);
+        let Some(mode) = Mode::detect(&track) else {
+            return DecodeResult::NoneFound;
+        };
+
+        // Line syncs can be detected without the decoder being allowed to start from them,
+        // so only switch over once it has started
+        let mut decoder = mode.decoder(self.options.clone());
+        let result = decoder.decode_track(&self.samples, &track);
+        if !matches!(result, DecodeResult::NoneFound) {
+            self.samples = Vec::new();
+            self.decoder = Some((mode, decoder));
+        }
+        result
+    }
+}
+
 /// Check if two floats are within 250 of eachother.
 /// Used for decoding.
 pub fn within_250hz(a: f64, b: f64) -> bool {
@@ -76,94 +532,706 @@ pub fn within_250hz(a: f64, b: f64) -> bool {
 /// detection more rigorous
 pub struct DSPOut<'a> {
     pub inner: &'a [f64],
-    pos: usize,
+    pos: f64,
+    /// Measured frequency offset of the signal in Hz, subtracted from every reading
+    freq_offset: f64,
+    /// Everything read so far, if tracing
+    events: Option<Vec<TraceEvent>>,
 }
 
 impl<'a> DSPOut<'a> {
-    pub fn new(from: &[f64]) -> DSPOut {
+    pub fn new(from: &[f64]) -> DSPOut<'_> {
         DSPOut {
             inner: from,
-            pos: 0,
+            pos: 0.,
+            freq_offset: 0.,
+            events: None,
+        }
+    }
+
+    /// Start recording everything read, to be collected with `take_events`
+    pub fn trace(&mut self) {
+        self.events.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the events recorded since tracing started or this was last called
+    pub fn take_events(&mut self) -> Vec<TraceEvent> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Record `event` if tracing
+    fn record(&mut self, event: TraceEvent) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
         }
     }
 
     /// This function will consume samples until they deviate by more than 250hz from
     /// the `frq` argument, returning Some(()) if it was successful.
     pub fn take_while_frq(&mut self, frq: f64) -> Option<()> {
-        let mut at = 0;
-        for i in self.pos..self.inner.len() {
-            if !within_250hz(*self.inner.get(i)?, frq) {
-                at = i;
-                break;
-            }
-        }
-
-        self.pos = at;
-        Some(())
+        self.take_while_frq_within(frq, 250.)
     }
 
+    /// Like `take_while_frq`, with a custom tolerance of `range` Hz.
+    ///
+    /// Returns None without moving if the samples run out first.
     pub fn take_while_frq_within(&mut self, frq: f64, range: f64) -> Option<()> {
-        let mut at = 0;
-        for i in self.pos..self.inner.len() {
-            if !((*self.inner.get(i)? - frq).abs() < range) {
-                at = i;
-                break;
-            }
-        }
+        let at = (self.index()..self.inner.len()).find(|&i| (self.freq(i) - frq).abs() >= range)?;
 
-        self.pos = at;
+        self.pos = at as f64;
         Some(())
     }
 
     /// This function will consume samples until they are less than 250Hz from
     /// the `frq` argument, returning Some(()) if it was successful.
+    ///
+    /// Returns None without moving if the samples run out first.
     pub fn take_till_frq(&mut self, frq: f64) -> Option<()> {
-        let mut at = 0;
-        for i in self.pos..self.inner.len() {
-            if within_250hz(*self.inner.get(i)?, frq) {
-                at = i;
-                break;
+        let at = (self.index()..self.inner.len()).find(|&i| within_250hz(self.freq(i), frq))?;
+
+        self.pos = at as f64;
+        Some(())
+    }
+
+    /// Wait for a sync pulse of `frq` Hz that's `len_us` long, consuming it.
+    ///
+    /// The smoothed frequency only crosses the 250Hz threshold partway through each edge of
+    /// the pulse, early at the start and late at the end, so the end is placed from the middle
+    /// of the detected pulse instead. Falls back to the detected end if the pulse is well
+    /// off its expected length, as noise cut it short.
+    pub fn take_sync(&mut self, frq: f64, len_us: f64) -> Option<SyncPulse> {
+        self.take_till_frq(frq)?;
+        let start = self.pos;
+        self.take_while_frq(frq)?;
+        let end = self.pos;
+
+        let len = us_to_samples(len_us);
+        let clean = ((end - start) - len).abs() < len / 2.;
+        if clean {
+            self.pos = (start + end) / 2. + len / 2.;
+        }
+
+        let sync = SyncPulse {
+            start: self.pos - len,
+            end: self.pos,
+            clean,
+        };
+        self.record(TraceEvent::Sync(sync));
+
+        Some(sync)
+    }
+
+    /// Find every pulse of `frq` Hz between `min_us` and `max_us` long from the current
+    /// position up to sample `to`, without consuming anything. Used to look at where the
+    /// syncs are in a signal, rather than to decode it.
+    pub fn find_syncs(&self, to: usize, frq: f64, min_us: f64, max_us: f64) -> Vec<SyncPulse> {
+        let (min, max) = (us_to_samples(min_us), us_to_samples(max_us));
+        let to = to.min(self.inner.len());
+
+        let mut syncs = Vec::new();
+        let mut i = self.index();
+        while i < to {
+            if !within_250hz(self.freq(i), frq) {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            while i < to && within_250hz(self.freq(i), frq) {
+                i += 1;
+            }
+
+            let len = (i - start) as f64;
+            if len >= min && len <= max {
+                syncs.push(SyncPulse {
+                    start: start as f64,
+                    end: i as f64,
+                    clean: true,
+                });
             }
         }
 
-        self.pos = at;
-        Some(())
+        syncs
+    }
+
+    /// Consume a scanline of `n` pixels, each `pixel_us` long, returning their luminance values.
+    pub fn take_scanline(&mut self, n: usize, pixel_us: f64) -> Option<Vec<u8>> {
+        let start = self.pos;
+
+        let mut values = Vec::with_capacity(n);
+        for _ in 0..n {
+            let pixel_start = self.pos;
+            let Some(freq) = self.take_us(pixel_us) else {
+                // Don't move if the scanline isn't all there yet
+                self.pos = start;
+                return None;
+            };
+
+            self.record(TraceEvent::Pixel {
+                start: pixel_start,
+                end: self.pos,
+                freq,
+            });
+            values.push(freq_to_value(freq));
+        }
+
+        Some(values)
     }
 
     /// Consume `us` micro-seconds worth of samples, returning Some with
     /// the average frequency throught said samples if successful.
+    ///
+    /// The position is kept to a fraction of a sample, so lengths that aren't a whole
+    /// number of samples don't drift. Samples only partly inside the window are weighted
+    /// by how much of them is inside, which makes this a matched filter for the
+    /// rectangular pixels SSTV sends.
     pub fn take_us(&mut self, us: f64) -> Option<f64> {
-        let total_samples = us_to_n_samples(us);
+        let len = us_to_samples(us);
+        let (start, end) = (self.pos, self.pos + len);
 
-        let mut sum = 0.;
+        if end.ceil() as usize > self.inner.len() || len <= 0. {
+            return None;
+        }
 
-        for i in self.pos..(self.pos + total_samples) {
-            sum += self.inner.get(i)?;
+        // Sample i covers the time from i to i + 1
+        let mut sum = 0.;
+        for i in start.floor() as usize..end.ceil() as usize {
+            let overlap = (end.min(i as f64 + 1.) - start.max(i as f64)).max(0.);
+            sum += self.freq(i) * overlap;
         }
 
-        self.pos += total_samples;
+        self.pos = end;
 
-        Some(sum / (total_samples as f64))
+        Some(sum / len)
     }
 
     /// Set the position over the samples
-    pub fn set_to(&mut self, pos: usize) {
+    pub fn set_to(&mut self, pos: f64) {
         self.pos = pos;
     }
 
-    /// Get the position over the samples
-    pub fn get_pos(&self) -> usize {
+    /// Get the position over the samples, which can be part way through a sample
+    pub fn get_pos(&self) -> f64 {
         self.pos
     }
+
+    /// Set the frequency offset of the signal, which is subtracted from every reading
+    pub fn set_freq_offset(&mut self, offset: f64) {
+        self.freq_offset = offset;
+    }
+
+    /// Get the frequency offset of the signal
+    pub fn get_freq_offset(&self) -> f64 {
+        self.freq_offset
+    }
+
+    /// Index of the sample the position is in
+    fn index(&self) -> usize {
+        self.pos as usize
+    }
+
+    /// The frequency at sample `i`, corrected for the frequency offset
+    fn freq(&self, i: usize) -> f64 {
+        self.inner[i] - self.freq_offset
+    }
+}
+
+/// A sync pulse found by `DSPOut::take_sync` or `DSPOut::find_syncs`
+#[derive(Clone, Copy, Debug)]
+pub struct SyncPulse {
+    /// Sample the pulse starts at
+    pub start: f64,
+    /// Sample the pulse ends at
+    pub end: f64,
+    /// Whether the pulse was about the expected length. If not, its position is a guess
+    pub clean: bool,
+}
+
+/// A calibration header found by `get_calibration_header`
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationHeader {
+    /// The 7 bit VIS code
+    pub vis: u8,
+    /// Sample the first leader tone starts at
+    pub start: usize,
+    /// Sample the VIS code's start bit starts at
+    pub vis_start: usize,
+}
+
+/// The shortest leader tone accepted when looking for a calibration header
+const MIN_LEADER_US: f64 = 50_000.;
+
+/// Furthest a VIS bit can be from its tone in Hz before the header is rejected
+const VIS_BIT_TOLERANCE: f64 = 80.;
+
+/// This function looks for the calibration header in the samples, returning
+/// the 7 bit VIS code if one is found.
+///
+/// The header is described in `Signal::push_calibration_header`. Leader tones
+/// of any length above 50ms are accepted. Anything that looks like a leader but isn't
+/// followed by a proper VIS code, such as bright parts of an image, is skipped over.
+///
+/// The leader is used to measure how far the signal is mistuned, which gets set as the
+/// frequency offset of `sig` so everything after it is read corrected.
+pub fn get_calibration_header(sig: &mut DSPOut) -> Option<CalibrationHeader> {
+    let freq_offset = sig.freq_offset;
+    let events = sig.events.as_ref().map_or(0, Vec::len);
+
+    loop {
+        let retry = match read_calibration_header(sig) {
+            Ok(header) => return Some(header),
+            Err(retry) => retry,
+        };
+
+        // Forget the false start before trying again or giving up
+        sig.freq_offset = freq_offset;
+        if let Some(recorded) = &mut sig.events {
+            recorded.truncate(events);
+        }
+
+        // What was taken as the second leader could really be the first, with a glitch
+        // before it taken for the first, so carry on looking from there
+        sig.set_to(retry? as f64);
+    }
+}
+
+/// Read a calibration header from the next leader tone, see `get_calibration_header`.
+///
+/// When it isn't a proper header, gives the sample the second leader started at if one was
+/// found, or None if the samples ran out before then.
+fn read_calibration_header(sig: &mut DSPOut) -> Result<CalibrationHeader, Option<usize>> {
+    sig.take_till_frq(1900.).ok_or(None)?;
+    let header_start = sig.index();
+
+    sig.take_while_frq_within(1900., 400.).ok_or(None)?;
+
+    // Skip the break between the leaders
+    sig.take_till_frq(1200.).ok_or(None)?;
+    sig.take_till_frq(1900.).ok_or(None)?;
+
+    // Measure the second leader
+    let start = sig.index();
+    let retry = Some(start);
+    sig.take_while_frq_within(1900., 400.).ok_or(retry)?;
+    let leader = &sig.inner[start..sig.index()];
+
+    if leader.len() < us_to_n_samples(MIN_LEADER_US) {
+        return Err(retry);
+    }
+
+    let avg = leader.iter().sum::<f64>() / leader.len() as f64;
+
+    if (avg - 1900.).abs() > 200. {
+        return Err(retry);
+    }
+
+    sig.set_freq_offset(avg - 1900.);
+    let vis_start = sig.index();
+
+    take_vis_bit(sig, VisBit::Start).ok_or(retry)?;
+
+    let mut vis = 0;
+
+    for bit in 0..7 {
+        if take_vis_bit(sig, VisBit::Data(bit)).ok_or(retry)? {
+            vis |= 1 << bit;
+        }
+    }
+
+    // The parity bit isn't checked, a corrupt VIS code still leaves a decodable image
+    let _parity = take_vis_bit(sig, VisBit::Parity).ok_or(retry)?;
+
+    take_vis_bit(sig, VisBit::Stop).ok_or(retry)?;
+
+    let header = CalibrationHeader {
+        vis,
+        start: header_start,
+        vis_start,
+    };
+    sig.record(TraceEvent::Header {
+        header,
+        end: sig.pos,
+        freq_offset: sig.freq_offset,
+    });
+
+    Ok(header)
+}
+
+/// Read a 30ms bit of a VIS code, true being a 1
+///
+/// None if the bit isn't at the frequency it should be, meaning this isn't really a header.
+fn take_vis_bit(sig: &mut DSPOut, kind: VisBit) -> Option<bool> {
+    let start = sig.pos;
+    let freq = sig.take_us(30_000.)?;
+    sig.record(TraceEvent::VisBit {
+        kind,
+        start,
+        end: sig.pos,
+        freq,
+    });
+
+    let near = |tone: f64| (freq - tone).abs() < VIS_BIT_TOLERANCE;
+    match kind {
+        VisBit::Start | VisBit::Stop => near(1200.).then_some(false),
+        VisBit::Data(_) | VisBit::Parity => (near(1100.) || near(1300.)).then_some(freq < 1200.),
+    }
+}
+
+/// Fewest evenly spaced syncs in a row that `find_line_syncs` takes as a signal
+const MIN_SYNCED_LINES: usize = 4;
+/// How far a sync can be from where the line length puts it in μs
+const LINE_SYNC_TOLERANCE_US: f64 = 1500.;
+/// Most lines in a row that can have their sync missed in a run of syncs
+const MAX_MISSED_SYNCS: usize = 2;
+
+/// A run of evenly spaced syncs found by `find_line_syncs`
+#[derive(Clone, Copy, Debug)]
+pub struct LineSync {
+    /// The mode whose line length the syncs are spaced by
+    pub mode: Mode,
+    /// Sample the first sync of the run starts at
+    pub start: f64,
+    /// Number of syncs in the run
+    pub lines: usize,
+    /// How far the signal is mistuned in Hz, measured from the syncs
+    pub freq_offset: f64,
+}
+
+/// Look for syncs spaced one line of a mode apart, for starting to decode a signal whose
+/// header was missed.
+///
+/// Syncs are looked for from the current position of `sig`, and have to come in a run of
+/// at least `MIN_SYNCED_LINES` to count, allowing for a few being lost in noise. The mode
+/// out of `modes` with the longest run wins, and the run's first sync is where its lines
+/// start.
+pub fn find_line_syncs(sig: &DSPOut, modes: &[Mode]) -> Option<LineSync> {
+    let syncs = sig.find_syncs(sig.inner.len(), 1200., 2000., 12_000.);
+    let tolerance = us_to_samples(LINE_SYNC_TOLERANCE_US);
+
+    let mut best: Option<(Mode, Vec<&SyncPulse>)> = None;
+    for &mode in modes {
+        let line = us_to_samples(mode.line_us());
+
+        for (i, first) in syncs.iter().enumerate() {
+            // Follow the run on from this sync, each next sync being a whole number of
+            // lines after the last
+            let mut run = vec![first];
+            for sync in &syncs[i + 1..] {
+                let last = run[run.len() - 1];
+                let lines = ((sync.start - last.start) / line).round();
+
+                if lines > (MAX_MISSED_SYNCS + 1) as f64 {
+                    break;
+                }
+                if lines >= 1. && (sync.start - last.start - lines * line).abs() < tolerance {
+                    run.push(sync);
+                }
+            }
+
+            if run.len() >= MIN_SYNCED_LINES
+                && best.as_ref().is_none_or(|(_, best)| run.len() > best.len())
+            {
+                best = Some((mode, run));
+            }
+        }
+    }
+
+    let (mode, run) = best?;
+
+    // The middle of each sync is the cleanest part to measure the offset from
+    let freqs: Vec<f64> = run
+        .iter()
+        .flat_map(|sync| {
+            let quarter = (sync.end - sync.start) / 4.;
+            &sig.inner[(sync.start + quarter) as usize..(sync.end - quarter) as usize]
+        })
+        .copied()
+        .collect();
+    let freq_offset = freqs.iter().sum::<f64>() / freqs.len().max(1) as f64 - 1200.;
+
+    Some(LineSync {
+        mode,
+        start: run[0].start,
+        lines: run.len(),
+        freq_offset,
+    })
 }
 
 pub fn us_to_n_samples(s: f64) -> usize {
-    (SAMPLE_RATE as f64 * (s / 1_000_000.)).round() as usize
+    us_to_samples(s).round() as usize
+}
+
+/// The exact number of samples in `s` micro-seconds, including any fraction of a sample
+pub fn us_to_samples(s: f64) -> f64 {
+    SAMPLE_RATE as f64 * (s / 1_000_000.)
 }
 
 /// A decode result. Either finished, partial, or no image was found.
 pub enum DecodeResult {
-    Finished(DynamicImage),
-    Partial(DynamicImage),
+    Finished(DynamicImage, DecodeInfo),
+    Partial(DynamicImage, DecodeInfo),
     NoneFound,
 }
+
+/// Measurements made while decoding a signal, useful for ranking how well images
+/// were received
+#[derive(Clone, Debug)]
+pub struct DecodeInfo {
+    /// The mode being decoded
+    pub mode: Mode,
+    /// Estimated signal to noise ratio in dB within the 1-3KHz passband, measured over
+    /// the sync pulses
+    pub snr_db: f64,
+    /// Fraction of lines whose sync pulse was found cleanly, between 0 and 1
+    pub sync_rate: f64,
+    /// How far the signal is mistuned in Hz, positive meaning it's too high.
+    /// Measured from the leader tones and corrected for.
+    pub freq_offset: f64,
+    /// How much longer lines are than they should be in parts per million, from the
+    /// sender and receiver's sample clocks not matching. This shows up as a slanted image
+    pub slant_ppm: f64,
+    /// The calibration header decoding started from, or None if it started from the line
+    /// syncs
+    pub header: Option<CalibrationHeader>,
+    /// Sample the signal starts at, including the calibration header
+    pub start_sample: usize,
+    /// Sample decoding has got up to, the end of the signal once finished
+    pub end_sample: usize,
+    /// Quality of every decoded line between 0 and 1, the fraction of the power in its
+    /// sync pulse that's the sync tone rather than noise
+    pub line_quality: Vec<f64>,
+    /// Everything the decoder read, if `DecoderOptions::trace` is set. Only whole lines
+    /// are recorded
+    pub events: Vec<TraceEvent>,
+
+    // Running totals the above are worked out from
+    tone_power: f64,
+    noise_power: f64,
+    lines: usize,
+    clean_syncs: Vec<(f64, f64)>,
+}
+
+impl DecodeInfo {
+    pub fn new(mode: Mode) -> DecodeInfo {
+        DecodeInfo {
+            mode,
+            snr_db: 0.,
+            sync_rate: 0.,
+            freq_offset: 0.,
+            slant_ppm: 0.,
+            header: None,
+            start_sample: 0,
+            end_sample: 0,
+            line_quality: Vec::new(),
+            events: Vec::new(),
+            tone_power: 0.,
+            noise_power: 0.,
+            lines: 0,
+            clean_syncs: Vec::new(),
+        }
+    }
+
+    /// Add line `line`'s sync pulse to the measurements, returning the line's quality.
+    ///
+    /// `samples` are the raw samples being decoded, and `line_us` is how long each line
+    /// should be, used to measure the slant.
+    pub fn add_line(
+        &mut self,
+        samples: &[f32],
+        line: usize,
+        sync: &SyncPulse,
+        sync_frq: f64,
+        line_us: f64,
+    ) -> f64 {
+        // Only measure the middle of the pulse, clear of the edges where the tone changes
+        let margin = (sync.end - sync.start) / 4.;
+        let (tone, noise) = dsp::tone_power(
+            samples,
+            (sync.start + margin) as usize,
+            (sync.end - margin) as usize,
+            sync_frq + self.freq_offset,
+        );
+
+        self.tone_power += tone;
+        self.noise_power += noise;
+        self.snr_db = 10. * (self.tone_power / self.noise_power.max(f64::MIN_POSITIVE)).log10();
+
+        self.lines += 1;
+        if sync.clean {
+            self.clean_syncs.push((line as f64, sync.end));
+        }
+        self.sync_rate = self.clean_syncs.len() as f64 / self.lines as f64;
+
+        // Fit a line through where every clean sync ended up, the slope being how long
+        // lines actually are
+        if let Some(slope) = fit_slope(&self.clean_syncs) {
+            self.slant_ppm = (slope / us_to_samples(line_us) - 1.) * 1e6;
+        }
+
+        tone / (tone + noise).max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Least squares slope through `points`, None if there aren't enough to fit
+fn fit_slope(points: &[(f64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    (points.len() >= 2 && variance > 0.).then(|| covariance / variance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp;
+
+    /// Demodulate `signal` the way the decoders do
+    fn demodulate(signal: &Signal) -> Vec<f64> {
+        dsp::demodulate(&signal.to_samples(), &DecoderOptions::default())
+    }
+
+    /// How long the filters delay the frequency track by in samples, measured from where a
+    /// step between black and white crosses grey
+    fn track_delay() -> f64 {
+        let mut signal = Signal::new();
+        signal.push(1500, 50_000.);
+        signal.push(2300, 50_000.);
+        let track = demodulate(&signal);
+
+        let step = us_to_samples(50_000.);
+        let crossing = (step as usize..track.len())
+            .find(|&i| track[i] > 1900.)
+            .unwrap();
+        crossing as f64 - step
+    }
+
+    /// How far from the end of a sync between tones of `before` and `after` Hz the position
+    /// ends up in samples, allowing for the track being delayed. Found with `take_sync` if
+    /// `centred`, otherwise from where the track crosses back out of the sync
+    fn sync_end_error(before: usize, after: usize, centred: bool) -> f64 {
+        let mut signal = Signal::new();
+        signal.push(before, 50_000.);
+        signal.push(1200, 4862.);
+        signal.push(after, 50_000.);
+        let track = demodulate(&signal);
+
+        // Start past the filters settling
+        let mut out = DSPOut::new(&track);
+        out.set_to(us_to_samples(25_000.));
+        if centred {
+            out.take_sync(1200., 4862.).unwrap();
+        } else {
+            out.take_till_frq(1200.).unwrap();
+            out.take_while_frq(1200.).unwrap();
+        }
+
+        out.get_pos() - us_to_samples(50_000. + 4862.) - track_delay()
+    }
+
+    #[test]
+    fn vis_code_and_parity() {
+        // Martin M1's VIS code 44 has 3 bits set, so its parity bit is a 1. Bit 7 isn't sent
+        // and mustn't count towards the parity
+        for vis in [44, 44 | 0x80] {
+            let mut signal = Signal::new();
+            let options = EncoderOptions {
+                vis: Some(vis),
+                ..EncoderOptions::default()
+            };
+            signal.push_calibration_header(Mode::MartinM1, &options);
+            signal.push(1500, 50_000.);
+            let track = demodulate(&signal);
+
+            let mut out = DSPOut::new(&track);
+            out.trace();
+            let header = get_calibration_header(&mut out).unwrap();
+            assert_eq!(header.vis, 44);
+
+            let parity = out.take_events().into_iter().find_map(|event| match event {
+                TraceEvent::VisBit {
+                    kind: VisBit::Parity,
+                    freq,
+                    ..
+                } => Some(freq),
+                _ => None,
+            });
+            assert!(parity.is_some_and(|freq| (freq - 1100.).abs() < 50.));
+        }
+    }
+
+    #[test]
+    fn take_sync_ends_on_the_sync() {
+        // Martin M1 syncs sit between two 1500Hz porches, where the crossing is 15 samples late
+        let error = sync_end_error(1500, 1500, true);
+        assert!(error.abs() < 4., "sync end {error:.1} samples out");
+
+        // Around pixels the crossing moves with how bright they are, which centring evens out
+        let total_error = |centred| {
+            [(1500, 1500), (1500, 2300), (2300, 1500), (2300, 2300)]
+                .into_iter()
+                .map(|(before, after)| sync_end_error(before, after, centred).abs())
+                .sum::<f64>()
+        };
+        let (centred, crossing) = (total_error(true), total_error(false));
+        assert!(
+            centred < crossing * 0.6,
+            "centred {centred:.1} samples out, crossing {crossing:.1}"
+        );
+    }
+
+    #[test]
+    fn ramp_starts_and_ends_at_0() {
+        let mut signal = Signal::new();
+        signal.push(1900, 100_000.);
+        let shaping = ToneShaping {
+            ramp_us: 5000.,
+            ..ToneShaping::default()
+        };
+        let samples = signal.to_samples_shaped(&shaping);
+        let peak = |samples: &[f32]| samples.iter().fold(0_f32, |peak, x| peak.max(x.abs()));
+
+        // 20 samples into a 220 sample ramp the envelope is still only at 2%
+        let ramp = us_to_n_samples(5000.);
+        assert!(peak(&samples[..20]) < 0.03);
+        assert!(peak(&samples[samples.len() - 20..]) < 0.03);
+        assert!(peak(&samples[ramp..samples.len() - ramp]) > 0.99);
+
+        let mut track = vec![None; 10];
+        track.extend([Some(1900.); 1000]);
+        track.extend([None; 10]);
+        let amplitudes = ramp_amplitudes(&track, 100);
+        assert!(amplitudes[..10].iter().all(|&amplitude| amplitude == 0.));
+        assert!(amplitudes[10] < 0.001);
+        assert!(amplitudes[10..110].is_sorted());
+        assert!(
+            amplitudes[110..910]
+                .iter()
+                .all(|&amplitude| amplitude == 1.)
+        );
+        assert!(amplitudes[1009] < 0.001);
+    }
+
+    #[test]
+    fn smoothing_keeps_steady_tones() {
+        let mut track = vec![1500.; 1000];
+        track.extend([2300.; 1000]);
+        let smoothed = smooth_transitions(&track, 88);
+
+        // Only the window's width around the step moves, and the tones either side are kept
+        let steady = |from: usize, to: usize, freq: f64| {
+            smoothed[from..to]
+                .iter()
+                .all(|smoothed| (smoothed - freq).abs() < 1e-9)
+        };
+        assert!(steady(0, 1000 - 44, 1500.));
+        assert!(steady(1000 + 44, 2000, 2300.));
+        assert!(smoothed.is_sorted());
+    }
+}
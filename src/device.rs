@@ -0,0 +1,369 @@
+use std::fmt;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc,
+};
+use std::time::Duration;
+
+use cpal::{
+    Device, FromSample, Host, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+    SupportedStreamConfig, SupportedStreamConfigRange,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+
+use crate::{
+    SAMPLE_RATE,
+    audio::{ChannelLayout, ChannelSelect, OutputOptions},
+    dsp,
+};
+
+/// An error while finding or opening an audio device
+#[derive(Debug)]
+pub enum DeviceError {
+    /// No host with the given name is available
+    HostNotFound(String),
+    /// No device matched the given name or index
+    NotFound(String),
+    /// The device has no config in a sample format we can use, with enough channels
+    NoConfig,
+    /// An error from the audio backend
+    Backend(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::HostNotFound(name) => write!(f, "no audio host called {name:?}"),
+            DeviceError::NotFound(selector) => write!(f, "no audio device matching {selector:?}"),
+            DeviceError::NoConfig => {
+                write!(f, "the device has no usable sample format or channel")
+            }
+            DeviceError::Backend(err) => write!(f, "audio backend error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// Turn any of cpal's error types into a `DeviceError::Backend`
+fn backend(err: impl fmt::Display) -> DeviceError {
+    DeviceError::Backend(err.to_string())
+}
+
+/// A device as shown by `list_devices`
+pub struct DeviceInfo {
+    /// Index used to select the device
+    pub index: usize,
+    pub name: String,
+    /// Most channels supported by any config
+    pub channels: u16,
+    /// Range of supported sample rates across every config
+    pub min_rate: u32,
+    pub max_rate: u32,
+}
+
+/// An audio host (ALSA, JACK, WASAPI...) and its devices
+pub struct HostInfo {
+    pub name: String,
+    pub inputs: Vec<DeviceInfo>,
+    pub outputs: Vec<DeviceInfo>,
+}
+
+/// List every available host along with its input and output devices.
+pub fn list_devices() -> Vec<HostInfo> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| cpal::host_from_id(id).ok())
+        .map(|host| HostInfo {
+            name: host.id().name().to_string(),
+            inputs: host
+                .input_devices()
+                .map(|devices| describe(devices, |d| d.supported_input_configs().ok()))
+                .unwrap_or_default(),
+            outputs: host
+                .output_devices()
+                .map(|devices| describe(devices, |d| d.supported_output_configs().ok()))
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn describe<I: Iterator<Item = SupportedStreamConfigRange>>(
+    devices: impl Iterator<Item = Device>,
+    configs: impl Fn(&Device) -> Option<I>,
+) -> Vec<DeviceInfo> {
+    devices
+        .enumerate()
+        .map(|(index, device)| {
+            let configs: Vec<_> = configs(&device).into_iter().flatten().collect();
+
+            DeviceInfo {
+                index,
+                name: device.name().unwrap_or_else(|_| "unknown".to_string()),
+                channels: configs.iter().map(|c| c.channels()).max().unwrap_or(0),
+                min_rate: configs
+                    .iter()
+                    .map(|c| c.min_sample_rate().0)
+                    .min()
+                    .unwrap_or(0),
+                max_rate: configs
+                    .iter()
+                    .map(|c| c.max_sample_rate().0)
+                    .max()
+                    .unwrap_or(0),
+            }
+        })
+        .collect()
+}
+
+/// Find a host by name (case insensitive), or the default host if `name` is None.
+pub fn find_host(name: Option<&str>) -> Result<Host, DeviceError> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| DeviceError::HostNotFound(name.to_string()))?;
+
+    cpal::host_from_id(id).map_err(backend)
+}
+
+/// Pick a device by its index from `list_devices`, its exact name or part of its name.
+fn select_device(
+    devices: impl Iterator<Item = Device>,
+    selector: &str,
+) -> Result<Device, DeviceError> {
+    let devices: Vec<(String, Device)> = devices
+        .map(|device| (device.name().unwrap_or_default(), device))
+        .collect();
+
+    let position = match selector.parse::<usize>() {
+        Ok(index) if index < devices.len() => Some(index),
+        _ => devices
+            .iter()
+            .position(|(name, _)| name == selector)
+            .or_else(|| devices.iter().position(|(name, _)| name.contains(selector))),
+    };
+
+    position
+        .map(|position| devices.into_iter().nth(position).unwrap().1)
+        .ok_or_else(|| DeviceError::NotFound(selector.to_string()))
+}
+
+/// Find an input device by index or name, or the default input if `selector` is None.
+pub fn find_input_device(host: &Host, selector: Option<&str>) -> Result<Device, DeviceError> {
+    match selector {
+        Some(selector) => select_device(host.input_devices().map_err(backend)?, selector),
+        None => host
+            .default_input_device()
+            .ok_or_else(|| DeviceError::NotFound("default input".to_string())),
+    }
+}
+
+/// Find an output device by index or name, or the default output if `selector` is None.
+pub fn find_output_device(host: &Host, selector: Option<&str>) -> Result<Device, DeviceError> {
+    match selector {
+        Some(selector) => select_device(host.output_devices().map_err(backend)?, selector),
+        None => host
+            .default_output_device()
+            .ok_or_else(|| DeviceError::NotFound("default output".to_string())),
+    }
+}
+
+/// Sample formats we can stream, best first
+const FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Pick the config to stream with, out of those with at least `min_channels` channels.
+/// `SAMPLE_RATE` is preferred, otherwise the closest supported rate, which then needs resampling.
+pub fn negotiate_config(
+    configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    min_channels: u16,
+) -> Result<SupportedStreamConfig, DeviceError> {
+    let target = SAMPLE_RATE as u32;
+
+    let mut configs: Vec<_> = configs
+        .filter(|config| FORMATS.contains(&config.sample_format()))
+        .filter(|config| config.channels() >= min_channels)
+        .collect();
+    configs.sort_by_key(|config| FORMATS.iter().position(|f| *f == config.sample_format()));
+
+    configs
+        .into_iter()
+        .map(|config| {
+            let rate = target.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+            config.with_sample_rate(SampleRate(rate))
+        })
+        // Stable, so the preferred format wins between equally close rates
+        .min_by_key(|config| config.sample_rate().0.abs_diff(target))
+        .ok_or(DeviceError::NoConfig)
+}
+
+/// Start streaming from an input device, returning the stream along with a channel
+/// receiving chunks of mono samples at `SAMPLE_RATE`.
+///
+/// Audio stops being received once the stream is dropped.
+pub fn open_input(
+    device: &Device,
+    channel: ChannelSelect,
+) -> Result<(Stream, mpsc::Receiver<Vec<f32>>), DeviceError> {
+    let min_channels = match channel {
+        ChannelSelect::Mix => 1,
+        ChannelSelect::Channel(channel) => channel + 1,
+    };
+
+    let config = negotiate_config(
+        device.supported_input_configs().map_err(backend)?,
+        min_channels,
+    )?;
+
+    let (tx, rx) = mpsc::channel();
+
+    let stream = match config.sample_format() {
+        SampleFormat::I16 => build_input::<i16>(device, &config.config(), channel, tx),
+        SampleFormat::U16 => build_input::<u16>(device, &config.config(), channel, tx),
+        _ => build_input::<f32>(device, &config.config(), channel, tx),
+    }?;
+
+    // Start gathering data in another thread
+    stream.play().map_err(backend)?;
+
+    Ok((stream, rx))
+}
+
+fn build_input<T>(
+    device: &Device,
+    config: &StreamConfig,
+    channel: ChannelSelect,
+    tx: mpsc::Sender<Vec<f32>>,
+) -> Result<Stream, DeviceError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels;
+    let mut resampler = dsp::Resampler::new(config.sample_rate.0, SAMPLE_RATE as u32);
+
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _| {
+                let samples: Vec<f32> = data.iter().map(|s| s.to_sample::<f32>()).collect();
+
+                // The config has enough channels for the selected one, so this can't fail
+                if let Ok(mono) = channel.apply(&samples, channels) {
+                    // When data is received, send it over the tx channel to the main thread
+                    let _ = tx.send(resampler.process(&mono));
+                }
+            },
+            |err| println!("{:#?}", err),
+            None,
+        )
+        .map_err(backend)
+}
+
+/// Play samples from `Signal::to_samples` on an output device, blocking until they've all
+/// been played or `abort` gets set.
+///
+/// `progress` is called roughly every 100ms with the number of samples played so far and
+/// the total. Returns whether the whole signal was played.
+pub fn play(
+    device: &Device,
+    samples: &[f32],
+    options: &OutputOptions,
+    abort: &AtomicBool,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<bool, DeviceError> {
+    let config = negotiate_config(
+        device.supported_output_configs().map_err(backend)?,
+        options.layout.channels(),
+    )?;
+
+    // Resample and apply the gain and DC offset upfront, so the audio callback only has
+    // to copy
+    let gain = options.gain();
+    let resampled: Arc<[f32]> = dsp::Resampler::new(SAMPLE_RATE as u32, config.sample_rate().0)
+        .process(samples)
+        .into_iter()
+        .map(|sample| options.offset(sample * gain))
+        .collect();
+    let played = Arc::new(AtomicUsize::new(0));
+
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        SampleFormat::I16 => {
+            build_output::<i16>(device, &stream_config, options.layout, &resampled, &played)
+        }
+        SampleFormat::U16 => {
+            build_output::<u16>(device, &stream_config, options.layout, &resampled, &played)
+        }
+        _ => build_output::<f32>(device, &stream_config, options.layout, &resampled, &played),
+    }?;
+
+    stream.play().map_err(backend)?;
+
+    let total = resampled.len();
+    let to_input_samples = |n: usize| n * samples.len() / total.max(1);
+
+    while !abort.load(Ordering::Relaxed) {
+        let position = played.load(Ordering::Relaxed);
+        progress(to_input_samples(position), samples.len());
+
+        if position >= total {
+            // Give the device time to play out whatever it has buffered
+            std::thread::sleep(Duration::from_millis(200));
+            return Ok(true);
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(false)
+}
+
+fn build_output<T>(
+    device: &Device,
+    config: &StreamConfig,
+    layout: ChannelLayout,
+    samples: &Arc<[f32]>,
+    played: &Arc<AtomicUsize>,
+) -> Result<Stream, DeviceError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let samples = samples.clone();
+    let played = played.clone();
+
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut position = played.load(Ordering::Relaxed);
+
+                for frame in data.chunks_mut(channels) {
+                    // Silence once the signal is over
+                    let sample = samples.get(position).copied().unwrap_or(0.);
+                    position = (position + 1).min(samples.len());
+
+                    let values = layout.frame(sample);
+
+                    for (i, out) in frame.iter_mut().enumerate() {
+                        let value = match layout {
+                            // A mono signal goes out on every channel of the device
+                            ChannelLayout::Mono => sample,
+                            _ => values.get(i).copied().flatten().unwrap_or(0.),
+                        };
+                        *out = T::from_sample(value);
+                    }
+                }
+
+                played.store(position, Ordering::Relaxed);
+            },
+            |err| println!("{:#?}", err),
+            None,
+        )
+        .map_err(backend)
+}
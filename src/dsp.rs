@@ -1,8 +1,557 @@
 use core::f64;
 
+use biquad::{Biquad, Coefficients, DirectForm1, Type};
 use num_complex::Complex64;
 
-use crate::SAMPLE_RATE;
+use crate::{
+    SAMPLE_RATE,
+    common::{DecoderOptions, us_to_n_samples},
+};
+
+/// The whole DSP chain used by the decoders, turning raw audio samples into a
+/// frequency measurement for every sample.
+///
+/// Impulses are blanked out and the samples brought to a steady level by the AGC if
+/// enabled, bandpass filtered between 1KHz and 3KHz, optionally cleaned up by the
+/// adaptive noise reduction filter, then the frequency is measured by the demodulator
+/// picked in `options`.
+pub fn demodulate(samples: &[f32], options: &DecoderOptions) -> Vec<f64> {
+    // Sources can end before any audio arrives, and the Hilbert transform can't take nothing
+    if samples.is_empty() {
+        return Vec::new();
+    }
+
+    let mut samples: Vec<f64> = samples.iter().map(|sample| *sample as f64).collect();
+
+    if options.noise_blanker {
+        samples = NoiseBlanker::default().process(&samples);
+    }
+
+    if options.agc {
+        samples = Agc::default().process(&samples);
+    }
+
+    // IIR Bandpass filter, 1KHz to 3KHz passband
+    // TODO: some form of caching to speedup live decodes
+    // as the sample buffer grows from the stream from the microphone
+    // the filter will have to recalculate across every sample every time a
+    // live decode is requested, hindering lower buffer sizes
+    let mut biquad_lp = biquad(Type::LowPass, 3000.);
+    let mut biquad_hp = biquad(Type::HighPass, 1000.);
+
+    let mut filtered: Vec<f64> = samples
+        .iter()
+        .map(|elem| biquad_hp.run(biquad_lp.run(*elem)))
+        .collect();
+
+    if options.noise_reduction {
+        filtered = NoiseReduction::default().process(&filtered);
+    }
+
+    options.demodulator().demodulate(&filtered)
+}
+
+/// Samples of lead in filtered before a segment measured by `tone_power`, letting the
+/// bandpass settle
+const TONE_POWER_LEAD_IN: usize = 256;
+
+/// Measure the power of a tone at `freq` Hz in `samples[from..to]` along with the power
+/// of everything else, after the same bandpass as `demodulate`. Returns `(tone, noise)`.
+pub fn tone_power(samples: &[f32], from: usize, to: usize, freq: f64) -> (f64, f64) {
+    let to = to.min(samples.len());
+    if from >= to {
+        return (0., 0.);
+    }
+    let lead_in = from.min(TONE_POWER_LEAD_IN);
+
+    let mut biquad_lp = biquad(Type::LowPass, 3000.);
+    let mut biquad_hp = biquad(Type::HighPass, 1000.);
+    let filtered: Vec<f64> = samples[from - lead_in..to]
+        .iter()
+        .map(|elem| biquad_hp.run(biquad_lp.run(*elem as f64)))
+        .skip(lead_in)
+        .collect();
+
+    // Least squares fit of the tone's cosine and sine parts. Unlike a plain correlation
+    // this is exact for short windows that don't hold a whole number of cycles
+    let omega = f64::consts::TAU * freq / SAMPLE_RATE as f64;
+    let (mut cc, mut ss, mut cs, mut xc, mut xs) = (0., 0., 0., 0., 0.);
+    for (i, sample) in filtered.iter().enumerate() {
+        let (sin, cos) = (omega * i as f64).sin_cos();
+        cc += cos * cos;
+        ss += sin * sin;
+        cs += cos * sin;
+        xc += sample * cos;
+        xs += sample * sin;
+    }
+    let det = cc * ss - cs * cs;
+    if det <= 0. {
+        return (0., 0.);
+    }
+    let a = (xc * ss - xs * cs) / det;
+    let b = (xs * cc - xc * cs) / det;
+
+    let n = filtered.len() as f64;
+    let total = filtered.iter().map(|s| s * s).sum::<f64>() / n;
+    // Power of the fitted tone over the same samples
+    let tone = ((a * a * cc + b * b * ss + 2. * a * b * cs) / n).min(total);
+
+    (tone, total - tone)
+}
+
+/// Automatic gain control, following the RMS level of the signal and scaling it to a
+/// steady level so the rest of the chain sees the same level whatever the input.
+///
+/// The level follows increases quickly and decreases slowly, so a sudden loud signal
+/// is brought down fast without the gain pumping up during short gaps.
+pub struct Agc {
+    /// RMS level the signal is brought to
+    pub target_rms: f64,
+    /// Time constant for following increases in level in μs
+    pub attack_us: f64,
+    /// Time constant for following decreases in level in μs
+    pub release_us: f64,
+    /// The most the signal is amplified by, so silence isn't blown up into loud noise
+    pub max_gain: f64,
+}
+
+impl Default for Agc {
+    fn default() -> Self {
+        Agc {
+            target_rms: 0.5,
+            attack_us: 5_000.,
+            release_us: 200_000.,
+            max_gain: 1000.,
+        }
+    }
+}
+
+impl Agc {
+    pub fn process(&self, samples: &[f64]) -> Vec<f64> {
+        // One pole smoothing coefficients from the time constants
+        let coefficient = |us: f64| 1. - (-1e6 / (us * SAMPLE_RATE as f64)).exp();
+        let (attack, release) = (coefficient(self.attack_us), coefficient(self.release_us));
+
+        // Start at the target level to avoid a burst of gain at the start
+        let mut power = self.target_rms * self.target_rms;
+
+        samples
+            .iter()
+            .map(|sample| {
+                let instant = sample * sample;
+                let rate = if instant > power { attack } else { release };
+                power += (instant - power) * rate;
+
+                let gain = (self.target_rms / power.sqrt()).min(self.max_gain);
+                sample * gain
+            })
+            .collect()
+    }
+}
+
+/// Impulse noise blanker, silencing static crashes and clicks that would otherwise
+/// show up as bright streaks.
+///
+/// Samples far louder than the recent average level are treated as an impulse, and
+/// silenced along with a short stretch around them to catch the whole impulse.
+pub struct NoiseBlanker {
+    /// How many times louder than the average level a sample has to be to get blanked
+    pub threshold: f64,
+    /// Time silenced around each impulse in μs
+    pub blank_us: f64,
+    /// Time constant of the average level in μs
+    pub average_us: f64,
+}
+
+impl Default for NoiseBlanker {
+    fn default() -> Self {
+        NoiseBlanker {
+            threshold: 8.,
+            blank_us: 500.,
+            average_us: 20_000.,
+        }
+    }
+}
+
+/// Level the noise blanker takes as silence, about -120dBFS
+const SILENCE_LEVEL: f64 = 1e-6;
+
+impl NoiseBlanker {
+    pub fn process(&self, samples: &[f64]) -> Vec<f64> {
+        if samples.is_empty() {
+            return Vec::new();
+        }
+
+        let rate = 1. - (-1e6 / (self.average_us * SAMPLE_RATE as f64)).exp();
+        let half_blank = us_to_n_samples(self.blank_us / 2.);
+
+        // Average level over the stretch of samples starting at `from`
+        let window = us_to_n_samples(self.average_us).max(1);
+        let average = |from: usize| {
+            let stretch = &samples[from..(from + window).min(samples.len())];
+            stretch.iter().map(|s| s.abs()).sum::<f64>() / stretch.len() as f64
+        };
+
+        let mut level = average(0);
+
+        let mut out = samples.to_vec();
+        // Samples are blanked up to here
+        let mut blanked_to = 0;
+
+        for (i, sample) in samples.iter().enumerate() {
+            let magnitude = sample.abs();
+
+            // Coming out of silence there's no level to go by, and everything would be
+            // blanked as louder than nothing, so start again from the level of what follows
+            if level < SILENCE_LEVEL && magnitude >= SILENCE_LEVEL {
+                level = average(i);
+            }
+            let limit = self.threshold * level;
+
+            if magnitude > limit {
+                let from = i.saturating_sub(half_blank).max(blanked_to);
+                blanked_to = (i + half_blank + 1).min(samples.len());
+                out[from..blanked_to].fill(0.);
+            }
+
+            // Impulses are capped in the average so they don't raise the threshold much,
+            // while a signal getting louder still raises it
+            level += (magnitude.min(limit) - level) * rate;
+        }
+
+        out
+    }
+}
+
+/// Adaptive noise reduction, using an LMS adaptive line enhancer.
+///
+/// An adaptive filter learns to predict each sample from the ones a little before it.
+/// The tone is predictable and noise isn't, so the prediction is the tone with most of
+/// the noise left out. The filter has to keep up with the tone changing every pixel, so
+/// the adaptation rate trades noise reduction against smearing detail.
+pub struct NoiseReduction {
+    /// Number of filter taps
+    pub taps: usize,
+    /// Samples between the newest one in the filter and the one being predicted
+    pub delay: usize,
+    /// Normalised adaptation rate, between 0 and 2
+    pub rate: f64,
+}
+
+impl Default for NoiseReduction {
+    fn default() -> Self {
+        NoiseReduction {
+            taps: 64,
+            delay: 1,
+            rate: 0.01,
+        }
+    }
+}
+
+impl NoiseReduction {
+    pub fn process(&self, samples: &[f64]) -> Vec<f64> {
+        let mut weights = vec![0.; self.taps];
+        let history = self.taps + self.delay;
+
+        samples
+            .iter()
+            .enumerate()
+            .map(|(n, sample)| {
+                // Wait for enough history to fill the filter
+                let Some(start) = (n + 1).checked_sub(history) else {
+                    return *sample;
+                };
+                let inputs = &samples[start..start + self.taps];
+
+                let prediction: f64 = weights.iter().zip(inputs).map(|(w, x)| w * x).sum();
+                let power: f64 = inputs.iter().map(|x| x * x).sum();
+
+                // Normalised LMS update
+                let step = self.rate * (sample - prediction) / (power + 1e-9);
+                for (weight, input) in weights.iter_mut().zip(inputs) {
+                    *weight += step * input;
+                }
+
+                prediction
+            })
+            .collect()
+    }
+}
+
+/// A way of measuring the instantaneous frequency of the bandpassed signal, giving a
+/// frequency in Hz for every sample.
+///
+/// Each has its own tradeoff between noise robustness, time resolution and CPU.
+pub trait Demodulator {
+    fn demodulate(&self, samples: &[f64]) -> Vec<f64>;
+}
+
+/// The demodulators that can be picked for decoding
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DemodulatorKind {
+    /// Hilbert transform quadrature demodulation, see `quadrature_demod`
+    #[default]
+    Quadrature,
+    /// Timing zero crossings, see `ZeroCrossing`
+    ZeroCrossing,
+    /// Peak of a bank of sliding DFT bins, see `Goertzel`
+    Goertzel,
+    /// Phase locked loop tracking the subcarrier, see `Pll`
+    Pll,
+}
+
+impl DemodulatorKind {
+    /// The demodulator with its default settings
+    pub fn demodulator(&self) -> Box<dyn Demodulator> {
+        match self {
+            DemodulatorKind::Quadrature => Box::new(Quadrature),
+            DemodulatorKind::ZeroCrossing => Box::new(ZeroCrossing),
+            DemodulatorKind::Goertzel => Box::new(Goertzel::default()),
+            DemodulatorKind::Pll => Box::new(Pll::default()),
+        }
+    }
+}
+
+/// A biquad filter with a Q of 1 at `f0` Hz.
+///
+/// `Coefficients::from_params` divides `f0` by twice the sample rate rather than by the
+/// Nyquist frequency, leaving the filter at a quarter of the frequency it's given, so the
+/// normalised frequency is worked out here instead.
+fn biquad(filter: Type<f64>, f0: f64) -> DirectForm1<f64> {
+    let normalized = 2. * f0 / SAMPLE_RATE as f64;
+    DirectForm1::new(Coefficients::from_normalized_params(filter, normalized, 1.).unwrap())
+}
+
+/// Run a 400Hz low pass over a frequency track to smooth out the readings
+fn smooth_track(track: &mut [f64]) {
+    let mut biquad = biquad(Type::LowPass, 400.);
+
+    for elem in track.iter_mut() {
+        *elem = biquad.run(*elem);
+    }
+}
+
+/// Quadrature demodulation followed by a 400Hz low pass, the default demodulator.
+///
+/// Accurate and fast, but the instantaneous frequency gets very noisy at low SNR.
+pub struct Quadrature;
+
+impl Demodulator for Quadrature {
+    fn demodulate(&self, samples: &[f64]) -> Vec<f64> {
+        let mut res = quadrature_demod(samples);
+        smooth_track(&mut res);
+        res
+    }
+}
+
+/// Measures the frequency from the time between zero crossings, interpolated between
+/// samples, followed by a 400Hz low pass.
+///
+/// The cheapest demodulator, but noise adds spurious crossings so it suffers most at
+/// low SNR.
+pub struct ZeroCrossing;
+
+impl Demodulator for ZeroCrossing {
+    fn demodulate(&self, samples: &[f64]) -> Vec<f64> {
+        let mut res = vec![0.; samples.len()];
+
+        // Time of the last crossing in samples, interpolated between the two samples around it
+        let mut last_crossing: Option<f64> = None;
+        let mut freq = 0.;
+        let mut filled = 0;
+
+        for i in 1..samples.len() {
+            let (a, b) = (samples[i - 1], samples[i]);
+            if (a < 0.) == (b < 0.) || a == b {
+                continue;
+            }
+
+            let crossing = (i - 1) as f64 + a / (a - b);
+
+            // Two crossings per cycle
+            if let Some(last) = last_crossing {
+                freq = SAMPLE_RATE as f64 / (2. * (crossing - last));
+            }
+            last_crossing = Some(crossing);
+
+            res[filled..i].fill(freq);
+            filled = i;
+        }
+        res[filled..].fill(freq);
+
+        smooth_track(&mut res);
+        res
+    }
+}
+
+/// Measures the frequency from the peak of a bank of DFT bins between 1KHz and 2.6KHz,
+/// slid along the signal one sample at a time. The peak is interpolated between bins by
+/// fitting a parabola.
+///
+/// The most robust against noise as each reading averages over the whole window, at the
+/// cost of time resolution and a lot more CPU.
+pub struct Goertzel {
+    /// Length of the DFT window in μs
+    pub window_us: f64,
+    /// Spacing between bins in Hz
+    pub bin_spacing: f64,
+}
+
+impl Default for Goertzel {
+    fn default() -> Self {
+        Goertzel {
+            window_us: 1000.,
+            bin_spacing: 50.,
+        }
+    }
+}
+
+impl Demodulator for Goertzel {
+    fn demodulate(&self, samples: &[f64]) -> Vec<f64> {
+        let window = ((self.window_us * SAMPLE_RATE as f64 / 1e6).round() as usize).max(2);
+        let bins: Vec<f64> = (0..)
+            .map(|i| 1000. + i as f64 * self.bin_spacing)
+            .take_while(|freq| *freq <= 2600.)
+            .collect();
+
+        // Per bin rotation per sample, the rotation from the newest sample's phasor back to
+        // the one leaving the window, and the running phasor e^(-iωn) for the newest sample
+        let steps: Vec<Complex64> = bins
+            .iter()
+            .map(|freq| Complex64::from_polar(1., -f64::consts::TAU * freq / SAMPLE_RATE as f64))
+            .collect();
+        let back: Vec<Complex64> = steps
+            .iter()
+            .map(|step| step.conj().powu(window as u32))
+            .collect();
+        let mut phasors = vec![Complex64::ONE; bins.len()];
+        let mut sums = vec![Complex64::ZERO; bins.len()];
+        let mut powers = vec![0.; bins.len()];
+
+        // Working on the analytic signal stops the mirror image at negative frequencies
+        // leaking into the bins, which is significant with windows this short
+        let analytic = hilbert_transform::hilbert(samples);
+
+        let mut res = vec![0.; samples.len()];
+
+        for (n, sample) in analytic.iter().enumerate() {
+            let leaving = n.checked_sub(window).map(|old| analytic[old]);
+
+            for (k, sum) in sums.iter_mut().enumerate() {
+                *sum += phasors[k] * sample;
+
+                if let Some(leaving) = leaving {
+                    *sum -= phasors[k] * back[k] * leaving;
+                }
+
+                powers[k] = sum.norm_sqr();
+                phasors[k] *= steps[k];
+            }
+
+            // Renormalise the phasors now and then to stop rounding errors building up
+            if n % 1024 == 0 {
+                phasors
+                    .iter_mut()
+                    .for_each(|phasor| *phasor /= phasor.norm());
+            }
+
+            // The window is centred `window / 2` samples back
+            if let Some(out) = n.checked_sub(window / 2) {
+                res[out] = interpolate_peak(&powers, &bins, self.bin_spacing);
+            }
+        }
+
+        // No window is centred on the last few samples, so hold the last reading over them
+        let held = samples.len().saturating_sub(window / 2);
+        if held > 0 {
+            let last = res[held - 1];
+            res[held..].fill(last);
+        }
+
+        res
+    }
+}
+
+/// A second order phase locked loop, locking an oscillator onto the subcarrier and
+/// reading the frequency off of the oscillator.
+///
+/// The loop bandwidth sets how quickly it can follow the signal: narrower rejects more
+/// noise, but smears fast changes between pixels. As the loop already averages out
+/// noise, no low pass is run after it.
+pub struct Pll {
+    /// Noise bandwidth of the loop in Hz
+    pub loop_bandwidth: f64,
+    /// Damping factor of the loop, 1/√2 for a critically damped loop
+    pub damping: f64,
+}
+
+impl Default for Pll {
+    fn default() -> Self {
+        Pll {
+            loop_bandwidth: 800.,
+            damping: f64::consts::FRAC_1_SQRT_2,
+        }
+    }
+}
+
+impl Demodulator for Pll {
+    fn demodulate(&self, samples: &[f64]) -> Vec<f64> {
+        let to_rad = f64::consts::TAU / SAMPLE_RATE as f64;
+
+        // Loop filter gains from the natural frequency, in radians per sample
+        let zeta = self.damping;
+        let natural = 2. * self.loop_bandwidth / (zeta + 1. / (4. * zeta)) / SAMPLE_RATE as f64;
+        let kp = 2. * zeta * natural;
+        let ki = natural * natural;
+
+        // Keep the oscillator within the SSTV band so it can't wander off in silence
+        let (min, max) = (1000. * to_rad, 2600. * to_rad);
+
+        // The analytic signal makes the phase detector independent of the amplitude
+        // and free of the double frequency product a real mixer gives
+        let analytic = hilbert_transform::hilbert(samples);
+
+        let mut phase = 0.;
+        let mut freq = 1900. * to_rad;
+
+        analytic
+            .iter()
+            .map(|sample| {
+                let error = (sample * Complex64::from_polar(1., -phase)).arg();
+
+                freq = (freq + ki * error).clamp(min, max);
+                phase = (phase + freq + kp * error) % f64::consts::TAU;
+
+                // The integrator holds the frequency the loop has settled on, without the
+                // noise the proportional path passes straight through
+                freq / to_rad
+            })
+            .collect()
+    }
+}
+
+/// Find the frequency of the peak in `powers`, fitting a parabola through the highest
+/// bin and its neighbours.
+fn interpolate_peak(powers: &[f64], bins: &[f64], spacing: f64) -> f64 {
+    let Some((peak, _)) = powers.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)) else {
+        return 0.;
+    };
+
+    if peak == 0 || peak == powers.len() - 1 {
+        return bins[peak];
+    }
+
+    let (a, b, c) = (powers[peak - 1], powers[peak], powers[peak + 1]);
+    let denom = a - 2. * b + c;
+    let offset = if denom == 0. {
+        0.
+    } else {
+        0.5 * (a - c) / denom
+    };
+
+    bins[peak] + offset * spacing
+}
 
 /// This function does various DSP operations on the `samples` vec
 ///
@@ -28,8 +577,8 @@ use crate::SAMPLE_RATE;
 /// SAMPLE_RATE / (2 * pi)
 ///
 /// This means we can get a continuous frequency measurement over all the samples
-pub fn quadrature_demod(samples: &Vec<f64>) -> Vec<f64> {
-    let hilbert = hilbert_transform::hilbert(&samples);
+pub fn quadrature_demod(samples: &[f64]) -> Vec<f64> {
+    let hilbert = hilbert_transform::hilbert(samples);
 
     let mut prv = Complex64::ZERO;
 
@@ -44,3 +593,232 @@ pub fn quadrature_demod(samples: &Vec<f64>) -> Vec<f64> {
 
     ret
 }
+
+/// Resample audio from `from` Hz to `to` Hz with linear interpolation.
+///
+/// SSTV signals sit well below 3KHz, so linear interpolation is plenty at the
+/// sample rates audio usually comes in at.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    Resampler::new(from, to).process(samples)
+}
+
+/// A linear interpolation resampler for audio arriving in chunks, such as from
+/// a pipe or an audio device. See `resample`.
+pub struct Resampler {
+    /// Input samples per output sample
+    step: f64,
+    /// Position of the next output sample, relative to the last sample of the previous chunk
+    pos: f64,
+    /// The last sample of the previous chunk
+    prev: Option<f32>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Resampler {
+        Resampler::with_ratio(to as f64 / from as f64)
+    }
+
+    /// Resampler giving `ratio` output samples for every input sample, for ratios that
+    /// aren't between two whole sample rates
+    pub fn with_ratio(ratio: f64) -> Resampler {
+        Resampler {
+            step: 1. / ratio,
+            pos: 0.,
+            prev: None,
+        }
+    }
+
+    /// Resample the next chunk of audio
+    pub fn process(&mut self, chunk: &[f32]) -> Vec<f32> {
+        if self.step == 1. {
+            return chunk.to_vec();
+        }
+
+        let Some(&last) = chunk.last() else {
+            return Vec::new();
+        };
+
+        // Index into the previous chunk's last sample followed by this chunk
+        let prev = self.prev;
+        let get = |i: usize| match prev {
+            Some(prev) if i == 0 => prev,
+            Some(_) => chunk[i - 1],
+            None => chunk[i],
+        };
+        let len = chunk.len() + prev.is_some() as usize;
+
+        let mut out = Vec::with_capacity((chunk.len() as f64 / self.step) as usize + 1);
+
+        while (self.pos as usize) + 1 < len {
+            let at = self.pos as usize;
+            let frac = (self.pos - at as f64) as f32;
+
+            out.push(get(at) + (get(at + 1) - get(at)) * frac);
+            self.pos += self.step;
+        }
+
+        // Carry the position over so it's relative to the last sample of this chunk
+        self.pos -= (len - 1) as f64;
+        self.prev = Some(last);
+
+        out
+    }
+}
+
+/// Design a windowed-sinc FIR low pass filter, `n_taps` long with a cutoff of `cutoff`
+/// Hz at `sample_rate` Hz, using a Hamming window.
+pub fn lowpass_taps(cutoff: f64, sample_rate: f64, n_taps: usize) -> Vec<f64> {
+    let fc = cutoff / sample_rate;
+    let middle = (n_taps - 1) as f64 / 2.;
+
+    let taps: Vec<f64> = (0..n_taps)
+        .map(|i| {
+            let x = i as f64 - middle;
+            let sinc = if x == 0. {
+                2. * fc
+            } else {
+                (f64::consts::TAU * fc * x).sin() / (f64::consts::PI * x)
+            };
+            let window = 0.54 - 0.46 * (f64::consts::TAU * i as f64 / (n_taps - 1) as f64).cos();
+
+            sinc * window
+        })
+        .collect();
+
+    // Normalise to unity gain at DC
+    let total: f64 = taps.iter().sum();
+    taps.into_iter().map(|tap| tap / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tone at `freq` Hz with a peak of `amplitude`, `len` samples long
+    fn tone(freq: f64, amplitude: f64, len: usize) -> Vec<f64> {
+        let omega = f64::consts::TAU * freq / SAMPLE_RATE as f64;
+        (0..len)
+            .map(|i| amplitude * (omega * i as f64).sin())
+            .collect()
+    }
+
+    #[test]
+    fn noise_blanker_blanks_impulses() {
+        let mut samples = tone(1900., 0.1, SAMPLE_RATE);
+        samples[SAMPLE_RATE / 2] = 1.;
+
+        let out = NoiseBlanker::default().process(&samples);
+        assert_eq!(out[SAMPLE_RATE / 2], 0.);
+        assert_eq!(out[SAMPLE_RATE / 4], samples[SAMPLE_RATE / 4]);
+    }
+
+    #[test]
+    fn noise_blanker_after_silence() {
+        let mut samples = vec![0.; 2000];
+        samples.extend(tone(1900., 0.5, SAMPLE_RATE));
+
+        let out = NoiseBlanker::default().process(&samples);
+        assert_eq!(out, samples);
+    }
+
+    #[test]
+    fn noise_blanker_empty() {
+        assert!(NoiseBlanker::default().process(&[]).is_empty());
+
+        let options = DecoderOptions {
+            noise_blanker: true,
+            ..DecoderOptions::default()
+        };
+        assert!(demodulate(&[], &options).is_empty());
+    }
+
+    /// Peak gain of `filter` for a sine at `freq` Hz, once it has settled
+    fn gain(mut filter: DirectForm1<f64>, freq: f64) -> f64 {
+        let omega = f64::consts::TAU * freq / SAMPLE_RATE as f64;
+        (0..SAMPLE_RATE)
+            .map(|i| filter.run((omega * i as f64).sin()))
+            .skip(SAMPLE_RATE / 2)
+            .fold(0., |peak: f64, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn biquad_at_requested_frequency() {
+        // With a Q of 1 the gain at f0 is exactly 1
+        for (filter, f0) in [
+            (Type::LowPass, 3000.),
+            (Type::HighPass, 1000.),
+            (Type::LowPass, 400.),
+        ] {
+            let gain = gain(biquad(filter, f0), f0);
+            assert!((gain - 1.).abs() < 0.02, "gain {gain:.3} at {f0}Hz");
+        }
+
+        // White goes through the bandpass untouched, where a low pass at a quarter of 3KHz
+        // would take it down to a tenth
+        let white = gain(biquad(Type::LowPass, 3000.), 2300.);
+        assert!(white > 0.95, "gain {white:.3} at 2300Hz");
+    }
+
+    /// Tones at each frequency for the number of samples paired with it, keeping the phase
+    /// continuous between them
+    fn tones(parts: &[(f64, usize)]) -> Vec<f64> {
+        let mut phase: f64 = 0.;
+        parts
+            .iter()
+            .flat_map(|&(freq, len)| std::iter::repeat_n(freq, len))
+            .map(|freq| {
+                let sample = 0.5 * phase.sin();
+                phase += f64::consts::TAU * freq / SAMPLE_RATE as f64;
+                sample
+            })
+            .collect()
+    }
+
+    /// Furthest `demodulator` strays from `freq` over `range` of its reading of `samples`
+    fn worst_error(
+        demodulator: &dyn Demodulator,
+        samples: &[f64],
+        range: std::ops::Range<usize>,
+        freq: f64,
+    ) -> f64 {
+        demodulator.demodulate(samples)[range]
+            .iter()
+            .map(|reading| (reading - freq).abs())
+            .fold(0., f64::max)
+    }
+
+    #[test]
+    fn demodulators_track_tones_and_steps() {
+        // Readings are checked from 10ms after each change, leaving the filters time to
+        // settle and staying clear of the edges of the Hilbert transform. 1725Hz falls
+        // between two of Goertzel's bins
+        let settle = SAMPLE_RATE / 100;
+        let len = SAMPLE_RATE / 10;
+
+        for kind in [
+            DemodulatorKind::Quadrature,
+            DemodulatorKind::ZeroCrossing,
+            DemodulatorKind::Goertzel,
+            DemodulatorKind::Pll,
+        ] {
+            let demodulator = kind.demodulator();
+
+            for freq in [1500., 1725., 1900., 2300.] {
+                let samples = tones(&[(freq, len)]);
+                let error = worst_error(&*demodulator, &samples, settle..len - settle, freq);
+                assert!(error < 2., "{kind:?} read {freq}Hz up to {error:.2}Hz out");
+            }
+
+            for (from, to) in [(1500., 2300.), (2300., 1500.)] {
+                let samples = tones(&[(from, len), (to, len)]);
+                let error = worst_error(&*demodulator, &samples, settle..len - settle, from).max(
+                    worst_error(&*demodulator, &samples, len + settle..2 * len - settle, to),
+                );
+                assert!(
+                    error < 2.,
+                    "{kind:?} read a step from {from}Hz to {to}Hz up to {error:.2}Hz out"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use image::{DynamicImage, GrayImage, imageops::FilterType};
+
+use crate::common::{
+    DSPOut, DecodeInfo, DecodeResult, DecoderOptions, EncoderOptions, LineSync, Mode, SSTVMode,
+    Scan, Signal, find_line_syncs, get_calibration_header, us_to_samples,
+};
+use crate::dsp;
+
+/// Width of a FAX480 image in pixels
+const WIDTH: u32 = 512;
+/// Height of a FAX480 image in pixels
+const HEIGHT: u32 = 480;
+/// Length of a single pixel in μs
+const PIXEL_US: f64 = 512.;
+/// Length of the 1200Hz sync pulse starting each line in μs
+const SYNC_US: f64 = 5120.;
+
+/// Length of a whole line in μs
+pub(crate) const LINE_US: f64 = SYNC_US + WIDTH as f64 * PIXEL_US;
+
+/// The single luminance scan in each line, straight after the sync
+pub(crate) const SCANS: [Scan; 1] = [Scan {
+    start_us: SYNC_US,
+    pixels: WIDTH,
+    pixel_us: PIXEL_US,
+    channel: None,
+}];
+
+/// Number of alternating tones in the start signal
+const START_TONES: usize = 220;
+/// Length of each tone in the start signal in μs
+const START_TONE_US: f64 = 2048.;
+/// Number of white phasing lines sent before the image
+const PHASING_LINES: u32 = 20;
+
+/// How far before a sync found by `find_line_syncs` decoding starts from in μs, so the
+/// sync is waited for as normal
+const SYNC_LEAD_US: f64 = 2000.;
+
+/// A struct implementing the FAX480 SSTV mode
+///
+/// FAX480 is a black and white mode, 512x480 pixels with a 5.12ms sync at the start
+/// of every line. It only carries luminance, so images are decoded to `Luma8` and
+/// encoded from their luminance.
+///
+/// After the calibration header comes a start signal of 220 tones alternating between
+/// 2300Hz and 1500Hz, followed by 20 white phasing lines to let receivers lock onto
+/// the line timing before the image begins.
+pub struct FAX480 {
+    /// A cache of the decoded image to speed up decodes
+    decoded_image: GrayImage,
+    /// Buffer of every sample accumulated - calling FAX480::decode adds the passed sample list
+    /// to this vec
+    samples: Vec<f32>,
+
+    /// How the signal is processed before decoding
+    options: DecoderOptions,
+    /// Measurements of the signal being decoded
+    info: DecodeInfo,
+
+    // Used for caching in live decodes
+    in_partial_decode: bool,
+    pos: f64,
+    /// Current line, counting the phasing lines before the image
+    line: u32,
+}
+
+impl SSTVMode for FAX480 {
+    fn with_options(options: DecoderOptions) -> Self {
+        FAX480 {
+            options,
+            info: DecodeInfo::new(Mode::Fax480),
+            decoded_image: GrayImage::new(WIDTH, HEIGHT),
+            samples: Vec::new(),
+            in_partial_decode: false,
+            pos: 0.,
+            line: 0,
+        }
+    }
+
+    fn encode_with_options(&mut self, image: DynamicImage, options: &EncoderOptions) -> Signal {
+        let resize = image
+            .resize_exact(WIDTH, HEIGHT, FilterType::Nearest)
+            .to_luma8();
+
+        let mut out = Signal::new();
+        out.push_calibration_header(Mode::Fax480, options);
+
+        // Start signal
+        for i in 0..START_TONES {
+            out.push(if i % 2 == 0 { 2300 } else { 1500 }, START_TONE_US);
+        }
+
+        // Phasing lines
+        for _ in 0..PHASING_LINES {
+            sync(&mut out);
+            out.push_scanline([u8::MAX; WIDTH as usize], PIXEL_US);
+        }
+
+        for row in resize.rows() {
+            sync(&mut out);
+            out.push_scanline(row.map(|pixel| pixel.0[0]), PIXEL_US);
+        }
+
+        // Add a 100ms break at the end
+        out.push(0, 100_000.);
+        out
+    }
+
+    fn decode(&mut self, audio: &[f32]) -> DecodeResult {
+        self.samples.extend_from_slice(audio);
+
+        let filtered_dsp = dsp::demodulate(&self.samples, &self.options);
+        self.read(&filtered_dsp)
+    }
+
+    fn decode_track(&mut self, audio: &[f32], track: &[f64]) -> DecodeResult {
+        self.samples.extend_from_slice(audio);
+        self.read(track)
+    }
+
+    fn get_image(&self) -> DynamicImage {
+        DynamicImage::ImageLuma8(self.decoded_image.clone())
+    }
+}
+
+impl FAX480 {
+    /// Decode from the demodulated `track` of every sample so far, carrying on from where
+    /// the last decode left off
+    fn read(&mut self, track: &[f64]) -> DecodeResult {
+        let mut out = DSPOut::new(track);
+        out.set_to(self.pos);
+        out.set_freq_offset(self.info.freq_offset);
+        if self.options.trace {
+            out.trace();
+        }
+
+        // Look for the header and skip the start signal, exiting if either isn't there yet
+        if !self.in_partial_decode {
+            if let Some(header) = get_calibration_header(&mut out)
+                .filter(|_| out.take_us(START_TONES as f64 * START_TONE_US).is_some())
+            {
+                self.info.freq_offset = out.get_freq_offset();
+                self.info.header = Some(header);
+                self.info.start_sample = header.start;
+            } else if let Some(sync) = self.find_line_syncs(&mut out) {
+                // Joined partway through. There's no telling which row this line was sent
+                // as, so skip the phasing lines and start filling the image from the top
+                self.info.freq_offset = sync.freq_offset;
+                self.info.start_sample = sync.start as usize;
+                self.line = PHASING_LINES;
+            } else {
+                return DecodeResult::NoneFound;
+            }
+            self.info.events.extend(out.take_events());
+        }
+
+        for line in self.line..PHASING_LINES + HEIGHT {
+            let start_pos = out.get_pos();
+
+            // Wait for the sync pulse, then take a whole line
+            let Some((sync, values)) = out.take_sync(1200., SYNC_US).and_then(|sync| {
+                out.take_scanline(WIDTH as usize, PIXEL_US)
+                    .map(|values| (sync, values))
+            }) else {
+                self.pos = start_pos;
+                self.line = line;
+                self.in_partial_decode = true;
+                return DecodeResult::Partial(self.get_image(), self.info.clone());
+            };
+
+            // Phasing lines carry no image data, but their syncs still count
+            let quality = self
+                .info
+                .add_line(&self.samples, line as usize, &sync, 1200., LINE_US);
+            self.info.end_sample = out.get_pos() as usize;
+            self.info.events.extend(out.take_events());
+
+            let Some(row) = line.checked_sub(PHASING_LINES) else {
+                continue;
+            };
+            self.info.line_quality.push(quality);
+
+            for (x, value) in values.into_iter().enumerate() {
+                self.decoded_image.get_pixel_mut(x as u32, row).0[0] = value;
+            }
+        }
+
+        DecodeResult::Finished(self.get_image(), self.info.clone())
+    }
+
+    /// Look for a run of FAX480 line syncs if decoding without a header, moving `out` to
+    /// just before the first one
+    fn find_line_syncs(&self, out: &mut DSPOut) -> Option<LineSync> {
+        if !self.options.headerless {
+            return None;
+        }
+
+        out.set_to(self.pos);
+        out.set_freq_offset(0.);
+        let sync = find_line_syncs(out, &[Mode::Fax480])?;
+        out.set_to((sync.start - us_to_samples(SYNC_LEAD_US)).max(self.pos));
+        out.set_freq_offset(sync.freq_offset);
+        Some(sync)
+    }
+}
+
+/// Add a 1200Hz 5.12ms sync tone, placed at the start of every line
+fn sync(out: &mut Signal) {
+    out.push(1200, SYNC_US);
+}
@@ -0,0 +1,390 @@
+use std::f64::consts::TAU;
+use std::io::Read;
+use std::path::Path;
+
+use num_complex::Complex64;
+
+use crate::{SAMPLE_RATE, dsp};
+
+/// The sample format of an IQ recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum IqFormat {
+    /// Unsigned 8-bit pairs, as written by `rtl_sdr`
+    Cu8,
+    /// Signed 16-bit little endian pairs
+    Cs16,
+    /// 32-bit little endian float pairs
+    Cf32,
+}
+
+impl IqFormat {
+    /// Size of a single IQ pair in bytes
+    pub fn sample_size(&self) -> usize {
+        match self {
+            IqFormat::Cu8 => 2,
+            IqFormat::Cs16 => 4,
+            IqFormat::Cf32 => 8,
+        }
+    }
+
+    /// Convert an IQ pair from its raw bytes
+    fn read_sample(&self, bytes: &[u8]) -> Complex64 {
+        match self {
+            IqFormat::Cu8 => Complex64::new(
+                (bytes[0] as f64 - 127.5) / 128.,
+                (bytes[1] as f64 - 127.5) / 128.,
+            ),
+            IqFormat::Cs16 => Complex64::new(
+                i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 32768.,
+                i16::from_le_bytes([bytes[2], bytes[3]]) as f64 / 32768.,
+            ),
+            IqFormat::Cf32 => Complex64::new(
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+                f32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64,
+            ),
+        }
+    }
+}
+
+/// How the audio is demodulated from the IQ samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Demodulation {
+    /// Upper sideband
+    Usb,
+    /// Lower sideband
+    Lsb,
+    /// Narrowband FM, with 5KHz deviation
+    Nbfm,
+}
+
+/// Options describing an IQ recording and how to get audio out of it
+#[derive(Clone, Debug)]
+pub struct IqOptions {
+    pub format: IqFormat,
+    /// Sample rate of the IQ samples in Hz
+    pub sample_rate: u32,
+    /// Frequency of the signal relative to the centre of the recording in Hz.
+    /// For SSB this is the suppressed carrier, the dial frequency
+    pub offset_hz: f64,
+    /// Factor to decimate by before demodulating. Picked to land close to `SAMPLE_RATE`
+    /// if not set
+    pub decimation: Option<usize>,
+    pub demodulation: Demodulation,
+}
+
+/// Half the width of the passband of the SSB filter, centred on 1500Hz
+const SSB_HALF_WIDTH: f64 = 1500.;
+/// Frequency deviation of NBFM in Hz
+const NBFM_DEVIATION: f64 = 5000.;
+/// Cutoff of the channel filter before the NBFM discriminator
+const NBFM_CHANNEL: f64 = 6250.;
+
+/// A FIR filter which decimates as it goes, only computing the outputs it keeps
+struct FirDecimator {
+    taps: Vec<f64>,
+    factor: usize,
+    buf: Vec<Complex64>,
+    /// Index into `buf` of the start of the next output's window
+    next: usize,
+}
+
+impl FirDecimator {
+    fn new(taps: Vec<f64>, factor: usize) -> FirDecimator {
+        FirDecimator {
+            buf: vec![Complex64::ZERO; taps.len() - 1],
+            taps,
+            factor,
+            next: 0,
+        }
+    }
+
+    fn process(&mut self, input: &[Complex64]) -> Vec<Complex64> {
+        self.buf.extend_from_slice(input);
+
+        let mut out = Vec::with_capacity(input.len() / self.factor + 1);
+
+        while self.next + self.taps.len() <= self.buf.len() {
+            let window = &self.buf[self.next..self.next + self.taps.len()];
+            out.push(window.iter().zip(&self.taps).map(|(x, tap)| x * tap).sum());
+            self.next += self.factor;
+        }
+
+        // Drop everything no future output needs
+        self.buf.drain(..self.next);
+        self.next = 0;
+
+        out
+    }
+}
+
+/// Turns IQ samples into audio at `SAMPLE_RATE`, in chunks.
+///
+/// The signal is mixed down to baseband by the tuning offset, low pass filtered and
+/// decimated, then demodulated. SSB is demodulated by shifting the wanted sideband's
+/// 0-3KHz down around 0Hz, filtering, and shifting it back up before taking the real part.
+/// NBFM uses the same phase difference discriminator as `dsp::quadrature_demod`.
+pub struct IqDemodulator {
+    demodulation: Demodulation,
+    /// Phase and per-sample step of the oscillator mixing the signal to baseband
+    mix_phase: f64,
+    mix_step: f64,
+    decimator: FirDecimator,
+    /// Sample rate after decimating
+    rate: f64,
+    channel: FirDecimator,
+    /// Phase of the oscillator shifting SSB by `SSB_HALF_WIDTH`
+    shift_phase: f64,
+    prev: Complex64,
+    resampler: dsp::Resampler,
+}
+
+impl IqDemodulator {
+    pub fn new(options: &IqOptions) -> IqDemodulator {
+        let decimation = options
+            .decimation
+            .unwrap_or(options.sample_rate as usize / SAMPLE_RATE)
+            .max(1);
+        let rate = options.sample_rate as f64 / decimation as f64;
+
+        let anti_alias = dsp::lowpass_taps(
+            0.4 * rate,
+            options.sample_rate as f64,
+            (8 * decimation + 1).max(31),
+        );
+
+        let channel = match options.demodulation {
+            Demodulation::Usb | Demodulation::Lsb => dsp::lowpass_taps(SSB_HALF_WIDTH, rate, 255),
+            Demodulation::Nbfm => dsp::lowpass_taps(NBFM_CHANNEL.min(0.45 * rate), rate, 127),
+        };
+
+        IqDemodulator {
+            demodulation: options.demodulation,
+            mix_phase: 0.,
+            mix_step: -TAU * options.offset_hz / options.sample_rate as f64,
+            decimator: FirDecimator::new(anti_alias, decimation),
+            rate,
+            channel: FirDecimator::new(channel, 1),
+            shift_phase: 0.,
+            prev: Complex64::ZERO,
+            // The decimated rate is rarely a whole number of Hz, and rounding it would put a
+            // clock error on everything decoded
+            resampler: dsp::Resampler::with_ratio(SAMPLE_RATE as f64 / rate),
+        }
+    }
+
+    /// Demodulate the next chunk of IQ samples
+    pub fn process(&mut self, iq: &[Complex64]) -> Vec<f32> {
+        let mixed: Vec<Complex64> = iq
+            .iter()
+            .map(|sample| {
+                let out = sample * Complex64::from_polar(1., self.mix_phase);
+                self.mix_phase = (self.mix_phase + self.mix_step) % TAU;
+                out
+            })
+            .collect();
+
+        let baseband = self.decimator.process(&mixed);
+
+        let audio: Vec<f32> = match self.demodulation {
+            Demodulation::Usb | Demodulation::Lsb => {
+                let lsb = self.demodulation == Demodulation::Lsb;
+                let step = TAU * SSB_HALF_WIDTH / self.rate;
+                let start_phase = self.shift_phase;
+
+                // LSB is USB mirrored around the carrier
+                let shifted: Vec<Complex64> = baseband
+                    .iter()
+                    .enumerate()
+                    .map(|(i, sample)| {
+                        let sample = if lsb { sample.conj() } else { *sample };
+                        sample * Complex64::from_polar(1., -(start_phase + step * i as f64))
+                    })
+                    .collect();
+
+                let filtered = self.channel.process(&shifted);
+                self.shift_phase = (start_phase + step * baseband.len() as f64) % TAU;
+
+                filtered
+                    .iter()
+                    .enumerate()
+                    .map(|(i, sample)| {
+                        (sample * Complex64::from_polar(1., start_phase + step * i as f64)).re
+                            as f32
+                    })
+                    .collect()
+            }
+            Demodulation::Nbfm => self
+                .channel
+                .process(&baseband)
+                .into_iter()
+                .map(|sample| {
+                    let freq = (self.prev.conj() * sample).arg() * self.rate / TAU;
+                    self.prev = sample;
+                    (freq / NBFM_DEVIATION) as f32
+                })
+                .collect(),
+        };
+
+        self.resampler.process(&audio)
+    }
+}
+
+/// Reads IQ samples in chunks, demodulating them to audio at `SAMPLE_RATE`.
+pub struct IqReader<R: Read> {
+    reader: R,
+    format: IqFormat,
+    demodulator: IqDemodulator,
+    /// Bytes of a sample split across two reads
+    leftover: Vec<u8>,
+}
+
+impl<R: Read> IqReader<R> {
+    pub fn new(reader: R, options: &IqOptions) -> IqReader<R> {
+        IqReader {
+            reader,
+            format: options.format,
+            demodulator: IqDemodulator::new(options),
+            leftover: Vec::new(),
+        }
+    }
+
+    /// Read and demodulate the next chunk of up to `max_bytes` bytes, returning None once
+    /// the reader has ended.
+    pub fn read_chunk(&mut self, max_bytes: usize) -> std::io::Result<Option<Vec<f32>>> {
+        let mut buf = std::mem::take(&mut self.leftover);
+        let start = buf.len();
+        buf.resize(start + max_bytes, 0);
+
+        let read = loop {
+            match self.reader.read(&mut buf[start..]) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        buf.truncate(start + read);
+
+        let whole = buf.len() - buf.len() % self.format.sample_size();
+        self.leftover = buf.split_off(whole);
+
+        let iq: Vec<Complex64> = buf
+            .chunks_exact(self.format.sample_size())
+            .map(|bytes| self.format.read_sample(bytes))
+            .collect();
+
+        Ok(Some(self.demodulator.process(&iq)))
+    }
+}
+
+/// Load a whole IQ recording at `path`, demodulated to audio at `SAMPLE_RATE`.
+pub fn load_iq_file<P: AsRef<Path>>(path: P, options: &IqOptions) -> std::io::Result<Vec<f32>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut reader = IqReader::new(file, options);
+
+    let mut audio = Vec::new();
+    while let Some(chunk) = reader.read_chunk(1 << 16)? {
+        audio.extend(chunk);
+    }
+
+    Ok(audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Sample rate of the test recordings, decimated by 2 to 22050.5Hz
+    const IQ_RATE: u32 = 44_101;
+    /// Where the signal sits in the test recordings
+    const OFFSET: f64 = 5000.;
+    /// Audio tone sent in the test recordings
+    const TONE: f64 = 1900.;
+    /// Length of the test recordings in seconds
+    const SECONDS: usize = 5;
+
+    /// Demodulate a cf32 recording of `phase` at every sample as `demodulation`
+    fn demodulate(demodulation: Demodulation, phase: impl Fn(f64) -> f64) -> Vec<f32> {
+        let bytes: Vec<u8> = (0..IQ_RATE as usize * SECONDS)
+            .map(|i| Complex64::from_polar(0.5, phase(i as f64 / IQ_RATE as f64)))
+            .flat_map(|sample| [sample.re as f32, sample.im as f32])
+            .flat_map(f32::to_le_bytes)
+            .collect();
+
+        let options = IqOptions {
+            format: IqFormat::Cf32,
+            sample_rate: IQ_RATE,
+            offset_hz: OFFSET,
+            decimation: Some(2),
+            demodulation,
+        };
+        let mut reader = IqReader::new(Cursor::new(bytes), &options);
+
+        let mut audio = Vec::new();
+        while let Some(chunk) = reader.read_chunk(1 << 16).unwrap() {
+            audio.extend(chunk);
+        }
+        audio
+    }
+
+    /// Frequency of `audio` from the first to the last rising zero crossing, skipping the
+    /// first second while the filters settle
+    fn frequency(audio: &[f32]) -> f64 {
+        let audio = &audio[SAMPLE_RATE..];
+        let mean = audio.iter().sum::<f32>() / audio.len() as f32;
+
+        let crossings: Vec<f64> = audio
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] - mean < 0. && pair[1] - mean >= 0.)
+            .map(|(i, pair)| i as f64 + ((mean - pair[0]) / (pair[1] - pair[0])) as f64)
+            .collect();
+
+        let cycles = (crossings.len() - 1) as f64;
+        cycles * SAMPLE_RATE as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    /// Check `audio` is the whole recording long at `SAMPLE_RATE` and carries the tone
+    fn check(audio: &[f32]) {
+        let len = (SECONDS * SAMPLE_RATE) as f64;
+        assert!(
+            (audio.len() as f64 - len).abs() <= 2.,
+            "{} samples, expected {len}",
+            audio.len()
+        );
+
+        let freq = frequency(audio);
+        assert!((freq - TONE).abs() < 1., "tone came out at {freq:.2}Hz");
+    }
+
+    #[test]
+    fn usb() {
+        check(&demodulate(Demodulation::Usb, |t| {
+            TAU * (OFFSET + TONE) * t
+        }));
+    }
+
+    #[test]
+    fn lsb() {
+        check(&demodulate(Demodulation::Lsb, |t| {
+            TAU * (OFFSET - TONE) * t
+        }));
+    }
+
+    #[test]
+    fn nbfm() {
+        // Deviating by half the full deviation, with the phase the integral of the frequency
+        let deviation = NBFM_DEVIATION / 2.;
+        check(&demodulate(Demodulation::Nbfm, |t| {
+            TAU * OFFSET * t + deviation / TONE * (TAU * TONE * t).sin()
+        }));
+    }
+}
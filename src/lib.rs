@@ -1,17 +1,17 @@
-/// # RSSTV
-/// RSSTV is a SSTV transcoder written in rust, supporting encoding, decoding
-/// live streaming from the microphone.
-///
-/// ## Current features
-/// - Martin M1 transcoding
-/// - Microphone streaming
-/// - A base to work off to add more modes
-///
-/// ## Planned features
-/// - More modes
-/// - Faster decoding (currently takes 100ms per partial decode in a live decode)
-/// - A website powered by WASM
-///
+//! # RSSTV
+//! RSSTV is a SSTV transcoder written in rust, supporting encoding, decoding
+//! live streaming from the microphone.
+//!
+//! ## Current features
+//! - Martin M1 and FAX480 transcoding
+//! - Microphone streaming
+//! - A base to work off to add more modes
+//!
+//! ## Planned features
+//! - More modes
+//! - Faster decoding (currently takes 100ms per partial decode in a live decode)
+//! - A website powered by WASM
+//!
 
 /// `common` contains common code used by every mode and
 /// the traits required to implement them.
@@ -21,9 +21,42 @@ pub mod common;
 /// a time-frequency domain signal, suitable for decoding
 pub mod dsp;
 
+/// Reading and writing audio files
+pub mod audio;
+
+/// Demodulating audio out of IQ recordings from SDRs
+pub mod iq;
+
+/// Receiving audio over the network
+pub mod net;
+
+/// Simulating radio channels, for testing how well signals decode
+pub mod sim;
+
+/// Rendering spectrograms of signals, for seeing what's in a recording
+pub mod spectrogram;
+
+/// Recording what the decoders read, for debugging them
+pub mod trace;
+
+/// Cutting signals up into lines without syncing to them, for recovering images the
+/// decoders give up on
+pub mod unsynced;
+
 /// The Martin M1 mode transcoder
 pub mod martinm1;
 
+/// The FAX480 mode transcoder
+pub mod fax480;
+
+/// Finding and streaming to and from audio devices
+#[cfg(feature = "cli")]
+pub mod device;
+
+/// Keying radios for transmitting
+#[cfg(feature = "cli")]
+pub mod ptt;
+
 /// Wasm glue code
 #[cfg(feature = "wasm")]
 pub mod wasm;
@@ -1,34 +1,105 @@
-use std::sync::mpsc;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+};
 
 #[cfg(feature = "cli")]
-use cpal::{
-    SampleRate, StreamConfig,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-};
+use cpal::traits::DeviceTrait;
 use image::{ImageFormat, ImageReader};
 use rsstv::{
     SAMPLE_RATE,
-    common::{DecodeResult, SSTVMode},
-    martinm1::MartinM1,
+    audio::{
+        self, ChannelLayout, ChannelSelect, LevelMetrics, OutputOptions, RawFormat, RawReader,
+        SampleFormat,
+    },
+    common::{
+        DecodeInfo, DecodeResult, DecoderOptions, DetectingDecoder, EncoderOptions, Mode,
+        ToneShaping, us_to_n_samples, us_to_samples,
+    },
+    dsp::{self, DemodulatorKind},
+    iq::{Demodulation, IqFormat, IqOptions, IqReader},
+    net::NetSource,
+    sim::{self, ChannelOptions, Fading, Path, Tone},
+    spectrogram::{self, SpectrogramOptions},
+    trace::{self, TraceEvent},
+    unsynced::{self, UnsyncedOptions},
+};
+#[cfg(feature = "cli")]
+use rsstv::{
+    device,
+    ptt::{self, Ptt, PttError, PttOptions, RigctldPtt, SerialLine, SerialPtt},
 };
-
-use hound::{WavReader, WavSpec, WavWriter};
 
 #[cfg(feature = "cli")]
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 /// CLI argument struct, powered by clap
 #[cfg(feature = "cli")]
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Image or audio file to encode/decode from
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Image or audio file (WAV, FLAC, Ogg Vorbis or MP3) to encode/decode from,
+    /// `-` to decode from stdin
     #[clap()]
     input_file: Option<String>,
 
-    /// The file to write the output WAV to
+    /// The file to write the output WAV to, `-` for stdout
     #[clap(short, long, default_value = "out.wav")]
     ouput_file: String,
 
+    /// Read and write headerless raw PCM in this format instead of WAV. Raw input is
+    /// decoded live as it arrives
+    #[clap(long, value_enum)]
+    raw: Option<RawFormat>,
+
+    /// Sample rate of raw PCM input and output
+    #[clap(long, default_value_t = SAMPLE_RATE as u32)]
+    rate: u32,
+
+    /// Decode raw PCM received as UDP datagrams on this address, like GQRX and SDR++ send.
+    /// Uses the format from `--raw`, s16le by default
+    #[clap(long, value_name = "ADDR")]
+    udp: Option<String>,
+
+    /// Decode raw PCM streamed from a TCP server at this address
+    #[clap(long, value_name = "ADDR")]
+    tcp: Option<String>,
+
+    /// Decode raw PCM from the first TCP connection made to this address
+    #[clap(long, value_name = "ADDR")]
+    tcp_listen: Option<String>,
+
+    /// Decode from an IQ recording in this format, demodulating it first. Decoded live
+    /// as it arrives, like raw PCM
+    #[clap(long, value_enum)]
+    iq: Option<IqFormat>,
+
+    /// Sample rate of the IQ recording
+    #[clap(long, default_value_t = 2_400_000)]
+    iq_rate: u32,
+
+    /// Frequency of the signal relative to the centre of the IQ recording in Hz, the dial
+    /// frequency for SSB
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    offset: f64,
+
+    /// Factor to decimate the IQ samples by before demodulating, picked automatically if
+    /// not passed
+    #[clap(long)]
+    decimation: Option<usize>,
+
+    /// How to demodulate the IQ recording
+    #[clap(long, value_enum, default_value = "usb")]
+    demod: Demodulation,
+
     /// Decode flag - will encode if not passed
     #[clap(short, long)]
     decode: bool,
@@ -36,107 +107,469 @@ struct Args {
     /// Decode from the microphone audio input
     #[clap(short, long)]
     mic: bool,
+
+    /// Channel of a multichannel file or audio device to decode, counting from 0. Every
+    /// channel is mixed together if not passed
+    #[clap(long)]
+    channel: Option<u16>,
+
+    /// List the available audio hosts and devices
+    #[clap(long)]
+    list_devices: bool,
+
+    /// Audio host to use, the system default if not passed
+    #[clap(long)]
+    host: Option<String>,
+
+    /// Audio device to use, by index or name as shown by `--list-devices`. The default
+    /// device if not passed
+    #[clap(long)]
+    device: Option<String>,
+
+    /// The SSTV mode to encode/decode with. When decoding a file it's worked out from the
+    /// signal if not passed, otherwise Martin M1 is used
+    #[clap(long, value_enum)]
+    mode: Option<Mode>,
+
+    /// Start decoding from the line syncs if the header was missed. The lines received are
+    /// put at the top of the image, as there's no telling which rows they were sent as
+    #[clap(long)]
+    headerless: bool,
+
+    /// How the frequency of the signal is measured when decoding
+    #[clap(long, value_enum, default_value = "quadrature")]
+    demodulator: DemodulatorKind,
+
+    /// Loop bandwidth of the PLL demodulator in Hz. Narrower copes with weaker signals,
+    /// wider keeps more detail
+    #[clap(long, default_value_t = 800.)]
+    pll_bandwidth: f64,
+
+    /// Don't bring the input to a steady level before decoding
+    #[clap(long)]
+    no_agc: bool,
+
+    /// Blank out static crashes and clicks before decoding
+    #[clap(long)]
+    noise_blanker: bool,
+
+    /// Run adaptive noise reduction before measuring the frequency
+    #[clap(long)]
+    noise_reduction: bool,
+
+    /// Decode a file by cutting it into lines of the mode's length without looking at the
+    /// syncs, for recovering images the normal decoder can't sync to
+    #[clap(long)]
+    unsynced: bool,
+
+    /// Length of each line of an unsynced decode in ms, the mode's line length if not passed
+    #[clap(long)]
+    line_ms: Option<f64>,
+
+    /// Where the first line of an unsynced decode starts in ms, shifting the image sideways
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    line_offset_ms: f64,
+
+    /// Make the lines of an unsynced decode this many parts per million longer,
+    /// straightening a slanted image
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    slant_ppm: f64,
+
+    /// How far the signal is mistuned in Hz, for unsynced decodes. Normal decodes measure
+    /// it from the header
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    freq_offset: f64,
+
+    /// Put the pixels of an unsynced decode into the mode's colour channels rather than
+    /// showing whole lines in greyscale
+    #[clap(long)]
+    colour: bool,
+
+    /// When decoding a file, write everything the decoder read to this CSV: the header,
+    /// VIS bits, syncs and pixel windows
+    #[clap(long, value_name = "FILE")]
+    trace_events: Option<String>,
+
+    /// When decoding a file, write the frequency measured at every sample to this CSV
+    #[clap(long, value_name = "FILE")]
+    trace_track: Option<String>,
+
+    /// When decoding a file, plot the measured frequency and what the decoder read to this
+    /// PNG
+    #[clap(long, value_name = "FILE")]
+    trace_plot: Option<String>,
+
+    /// Where the trace plot starts in ms
+    #[clap(long, default_value_t = 0.)]
+    trace_from_ms: f64,
+
+    /// Where the trace plot ends in ms, the end of the file if not passed
+    #[clap(long)]
+    trace_to_ms: Option<f64>,
+
+    /// Width of the trace plot in pixels
+    #[clap(long, default_value_t = 2000)]
+    trace_width: u32,
+
+    /// Length of the 1000Hz preamble tone in ms, 0 to leave it out
+    #[clap(long, default_value_t = 200.)]
+    preamble_ms: f64,
+
+    /// Send the MMSSTV VOX tone sequence before the header
+    #[clap(long)]
+    vox: bool,
+
+    /// Length of each 1900Hz leader tone in ms
+    #[clap(long, default_value_t = 300.)]
+    leader_ms: f64,
+
+    /// Length of the 1200Hz break between the leader tones in ms
+    #[clap(long, default_value_t = 10.)]
+    break_ms: f64,
+
+    /// VIS code to send instead of the mode's own
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..128))]
+    vis: Option<u8>,
+
+    /// Smooth frequency changes along a raised cosine this many μs long, reducing splatter
+    #[clap(long, default_value_t = 0.)]
+    transition_us: f64,
+
+    /// Ramp the amplitude up and down over this many ms wherever the tone starts and stops
+    #[clap(long, default_value_t = 0.)]
+    ramp_ms: f64,
+
+    /// Play the encoded signal on an audio output device instead of writing it, selected
+    /// with `--host` and `--device`. Press enter to stop transmitting
+    #[clap(long)]
+    play: bool,
+
+    /// Key the radio through the RTS or DTR line of this serial port while playing
+    #[clap(long, value_name = "PORT")]
+    ptt_serial: Option<String>,
+
+    /// Serial line used with `--ptt-serial`
+    #[clap(long, value_enum, default_value = "rts")]
+    ptt_line: SerialLine,
+
+    /// Key the radio through the rigctld daemon at this address while playing,
+    /// usually localhost:4532
+    #[clap(long, value_name = "ADDR")]
+    rigctld: Option<String>,
+
+    /// Time between keying the radio and starting the audio in ms
+    #[clap(long, default_value_t = 200.)]
+    ptt_pre_ms: f64,
+
+    /// Time between the audio ending and unkeying the radio in ms
+    #[clap(long, default_value_t = 200.)]
+    ptt_post_ms: f64,
+
+    /// Peak level of the written audio in dBFS
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    level_dbfs: f64,
+
+    /// DC offset added to the written or played audio, as a fraction of full scale
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    dc_offset: f64,
+
+    /// Sample format of the written WAV
+    #[clap(long, value_enum, default_value = "float32")]
+    format: SampleFormat,
+
+    /// Channels to write the signal to
+    #[clap(long, value_enum, default_value = "mono")]
+    channels: ChannelLayout,
 }
 
+/// Tools run instead of encoding or decoding
 #[cfg(feature = "cli")]
-fn main() {
-    use hound::SampleFormat;
+#[derive(Subcommand)]
+enum Command {
+    /// Pass an audio file through a simulated radio channel, writing the impaired signal
+    /// to a WAV
+    Simulate(SimulateArgs),
+
+    /// Render a waterfall of an audio file to a PNG, marking the headers and syncs found
+    Spectrogram(SpectrogramArgs),
+}
 
-    let args = Args::parse();
+#[cfg(feature = "cli")]
+#[derive(clap::Args)]
+struct SimulateArgs {
+    /// Audio file to impair
+    input_file: String,
 
-    let mut mode = MartinM1::new();
+    /// The file to write the impaired WAV to
+    #[clap(short, long, default_value = "out.wav")]
+    output_file: String,
 
-    if args.decode {
-        if !args.mic {
-            // If decoding from a WAV file, load samples and decode all at once.
-            // Can also make the samples vec into an iterator to split into chunks,
-            // useful for testing live decodes.
-            let mut reader = WavReader::open(args.input_file.unwrap()).unwrap();
-            assert_eq!(reader.spec().sample_rate, SAMPLE_RATE as u32);
+    /// Add white noise at this signal to noise ratio in dB, measured over 3KHz
+    #[clap(long, allow_negative_numbers = true)]
+    snr: Option<f64>,
 
-            let samples: Vec<f32>;
+    /// Fade the signal like one of the standard HF channels
+    #[clap(long, value_enum)]
+    fading: Option<Fading>,
 
-            match (reader.spec().sample_format, reader.spec().bits_per_sample) {
-                (SampleFormat::Float, 32) => {
-                    samples = reader.samples().map(|s: Result<f32, _>| s.unwrap()).collect()
-                }
-                (SampleFormat::Int, 16) => {
-                    samples = reader.samples().map(|s: Result<i16, _>| s.unwrap() as f32).collect()
-                }
-                _ => todo!()
-            }
+    /// Add an echo of the signal arriving this many ms later
+    #[clap(long)]
+    echo_ms: Option<f64>,
+
+    /// Strength of the echo relative to the signal in dB
+    #[clap(long, default_value_t = -6., allow_negative_numbers = true)]
+    echo_db: f64,
+
+    /// Mistune the signal by this many Hz
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    freq_offset: f64,
+
+    /// Make the receiving sample clock this many parts per million fast, slanting the image
+    #[clap(long, default_value_t = 0., allow_negative_numbers = true)]
+    clock_ppm: f64,
+
+    /// Add an interfering tone, given as frequency in Hz and level relative to the signal
+    /// in dB, like `1500:-10`. Can be passed more than once
+    #[clap(long, value_name = "HZ:DB", allow_negative_numbers = true)]
+    tone: Vec<Tone>,
+
+    /// Filter the result to 300-2700Hz like an SSB receiver
+    #[clap(long)]
+    ssb_filter: bool,
+
+    /// Seed for the noise and fading
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Sample format of the written WAV
+    #[clap(long, value_enum, default_value = "float32")]
+    format: SampleFormat,
+}
+
+#[cfg(feature = "cli")]
+#[derive(clap::Args)]
+struct SpectrogramArgs {
+    /// Audio file to render
+    input_file: String,
+
+    /// The PNG file to write
+    #[clap(short, long, default_value = "spectrogram.png")]
+    output_file: String,
+
+    /// Length of the window each row is worked out over in ms
+    #[clap(long, default_value_t = 10.)]
+    window_ms: f64,
+
+    /// Time between rows in ms
+    #[clap(long, default_value_t = 10.)]
+    step_ms: f64,
+
+    /// Lowest frequency shown in Hz
+    #[clap(long, default_value_t = 500.)]
+    min_freq: f64,
+
+    /// Highest frequency shown in Hz
+    #[clap(long, default_value_t = 3000.)]
+    max_freq: f64,
+
+    /// Width of the waterfall in pixels
+    #[clap(long, default_value_t = 800)]
+    width: u32,
+
+    /// Range of levels shown in dB
+    #[clap(long, default_value_t = 60.)]
+    range_db: f64,
+
+    /// Don't look for headers and syncs to mark
+    #[clap(long)]
+    no_markers: bool,
+}
+
+#[cfg(feature = "cli")]
+fn main() {
+    let args = Args::parse();
 
-            let out = mode.decode(&samples.to_vec());
+    match args.command {
+        Some(Command::Simulate(args)) => return simulate(args),
+        Some(Command::Spectrogram(args)) => return render_spectrogram(args),
+        None => {}
+    }
 
-            match out {
-                DecodeResult::Finished(image) | DecodeResult::Partial(image) => {
-                    image.save_with_format("out.png", ImageFormat::Png).unwrap()
+    let decoder_options = DecoderOptions {
+        demodulator: args.demodulator,
+        pll_bandwidth: args.pll_bandwidth,
+        agc: !args.no_agc,
+        noise_blanker: args.noise_blanker,
+        noise_reduction: args.noise_reduction,
+        trace: args.trace_events.is_some()
+            || args.trace_track.is_some()
+            || args.trace_plot.is_some(),
+        headerless: args.headerless,
+    };
+    let mut mode_kind = args.mode.unwrap_or(Mode::MartinM1);
+    let mut mode = mode_kind.decoder(decoder_options.clone());
+
+    if args.list_devices {
+        for host in device::list_devices() {
+            println!("Host {}", host.name);
+
+            for (kind, devices) in [("Input", host.inputs), ("Output", host.outputs)] {
+                println!("  {kind} devices:");
+
+                for device in devices {
+                    println!(
+                        "    {}: {} ({} channels, {}-{}Hz)",
+                        device.index,
+                        device.name,
+                        device.channels,
+                        device.min_rate,
+                        device.max_rate
+                    );
                 }
-                DecodeResult::NoneFound => println!("No image found"),
             }
-        } else {
-            let mut decoder = MartinM1::new();
+        }
 
-            // If decoding from the mic, detect the default microphone
-            let host = cpal::default_host();
-            let device = host.default_input_device().unwrap();
+        return;
+    }
 
-            println!("using device {:?}", device.name().unwrap());
+    let channel = match args.channel {
+        Some(channel) => ChannelSelect::Channel(channel),
+        None => ChannelSelect::Mix,
+    };
 
-            let default_config = device.default_input_config().unwrap();
+    if args.decode {
+        let net_source = match (&args.udp, &args.tcp, &args.tcp_listen) {
+            (Some(addr), _, _) => Some(NetSource::Udp(addr.clone())),
+            (_, Some(addr), _) => Some(NetSource::TcpConnect(addr.clone())),
+            (_, _, Some(addr)) => Some(NetSource::TcpListen(addr.clone())),
+            _ => None,
+        };
 
-            // Set sample rate to 44.1KHz
-            // TODO: make it work at other sample rates
-            let mut config: StreamConfig = default_config.into();
-            config.sample_rate = SampleRate(SAMPLE_RATE as u32);
+        if let Some(source) = net_source {
+            let input = match source.open() {
+                Ok(input) => input,
+                Err(err) => {
+                    println!("failed to open {source:?}: {err}");
+                    return;
+                }
+            };
+
+            let format = args.raw.unwrap_or(RawFormat::S16le);
+            let mut reader = RawReader::new(input, format, args.rate);
+            let rx = spawn_source(move || reader.read_chunk(8192).ok().flatten());
+
+            live_decode(args.mode, &decoder_options, rx);
+        } else if let Some(format) = args.iq {
+            let options = IqOptions {
+                format,
+                sample_rate: args.iq_rate,
+                offset_hz: args.offset,
+                decimation: args.decimation,
+                demodulation: args.demod,
+            };
+
+            let mut reader = IqReader::new(open_input(args.input_file.as_deref()), &options);
+            let rx = spawn_source(move || reader.read_chunk(1 << 16).ok().flatten());
+
+            live_decode(args.mode, &decoder_options, rx);
+        } else if let Some(format) = args.raw {
+            // Raw PCM is usually piped in from another program, so decode it live as it comes in
+            let mut reader =
+                RawReader::new(open_input(args.input_file.as_deref()), format, args.rate);
+            let rx = spawn_source(move || reader.read_chunk(8192).ok().flatten());
+
+            live_decode(args.mode, &decoder_options, rx);
+        } else if !args.mic {
+            // If decoding from an audio file, load samples and decode all at once.
+            // Can also make the samples vec into an iterator to split into chunks,
+            // useful for testing live decodes.
+            let loaded = match args.input_file.as_deref() {
+                Some("-") => audio::load_wav(std::io::stdin(), channel),
+                Some(path) => audio::load_file(path, channel),
+                None => {
+                    println!("No input file given");
+                    return;
+                }
+            };
+
+            let samples = match loaded {
+                Ok(samples) => samples,
+                Err(err) => {
+                    println!("{err}");
+                    return;
+                }
+            };
 
-            // Multithread channels, `rx` will blockingly wait for a chunk of data
-            let (tx, rx) = mpsc::channel();
+            print_levels(&samples);
 
-            let stream = device
-                .build_input_stream(
-                    &config,
-                    move |data: &[f32], _| {
-                        // When data is received, send it over the tx channel to the main thread
-                        tx.send(data.to_vec()).unwrap();
-                    },
-                    |err| println!("{:#?}", err),
-                    None,
-                )
-                .unwrap();
-            // Start gathering data in another thread
-            stream.play().unwrap();
-
-            loop {
-                // Main thread logic:
-                let mut buf = Vec::new();
-
-                // Get data from streaming thread, accumulating into a bigger vec of at least 100k samples
-                // this makes the live decode not be the bottleneck in real time decodes, as it doesn't
-                // have to do the DSP processing as many times which takes roughly 100ms (which happens
-                // every time we call the decode fn) (filtering, quadrature demod)
-                // This adds up fast when sending buffers of just 512 samples.
-                // TODO: make it faster and send samples directly without accumulating
-                while buf.len() <= 100_000 {
-                    let mut received = rx.recv().unwrap();
-                    buf.append(&mut received);
+            // Demodulate once, for detecting the mode and decoding alike
+            let track = dsp::demodulate(&samples, &decoder_options);
+
+            if args.mode.is_none() {
+                match Mode::detect(&track) {
+                    Some(detected) => {
+                        println!("Detected {detected:?}");
+                        mode_kind = detected;
+                        mode = detected.decoder(decoder_options.clone());
+                    }
+                    None => println!("Couldn't detect the mode, trying {mode_kind:?}"),
                 }
+            }
+
+            if args.unsynced {
+                let mut options = UnsyncedOptions::for_mode(mode_kind);
+                if let Some(line_ms) = args.line_ms {
+                    options.line_us = line_ms * 1000.;
+                }
+                options.offset = us_to_samples(args.line_offset_ms * 1000.);
+                options.slant_ppm = args.slant_ppm;
+                options.freq_offset = args.freq_offset;
+                options.colour = args.colour.then_some(mode_kind);
+
+                let image = unsynced::render(&track, &options);
+                image.save_with_format("out.png", ImageFormat::Png).unwrap();
+                return;
+            }
 
-                let decode = decoder.decode(&buf);
+            let out = mode.decode_track(&samples, &track);
 
-                // Save image every time we call decode
-                if let DecodeResult::Partial(ref image) = decode {
+            let events = match out {
+                DecodeResult::Finished(image, info) | DecodeResult::Partial(image, info) => {
+                    print_start(&info);
+                    print_info(&info);
                     image.save_with_format("out.png", ImageFormat::Png).unwrap();
+                    info.events
                 }
-                if let DecodeResult::Finished(ref image) = decode {
-                    image.save_with_format("out.png", ImageFormat::Png).unwrap();
-                    break;
+                DecodeResult::NoneFound => {
+                    println!("No image found");
+                    Vec::new()
                 }
+            };
+
+            if decoder_options.trace {
+                write_trace(&args, &track, &events);
             }
+        } else {
+            // If decoding from the mic, find the selected microphone
+            let opened = device::find_host(args.host.as_deref())
+                .and_then(|host| device::find_input_device(&host, args.device.as_deref()))
+                .and_then(|device| {
+                    println!("using device {:?}", device.name().unwrap_or_default());
+                    device::open_input(&device, channel)
+                });
+
+            let (stream, rx) = match opened {
+                Ok(opened) => opened,
+                Err(err) => {
+                    println!("{err}");
+                    return;
+                }
+            };
+
+            live_decode(args.mode, &decoder_options, rx);
 
             // End streaming from the mic
             drop(stream);
-            println!("Finished decoding");
         }
     } else {
         // Else, we encode the image to audio!
@@ -147,29 +580,388 @@ fn main() {
             .decode()
             .unwrap();
 
+        let options = EncoderOptions {
+            preamble_us: args.preamble_ms * 1000.,
+            vox_tones: args.vox,
+            leader_us: args.leader_ms * 1000.,
+            break_us: args.break_ms * 1000.,
+            vis: args.vis,
+        };
+
         // Encode
-        let signal = mode.encode(reader);
+        let signal = mode.encode_with_options(reader, &options);
 
         // And write
-        let written: &[f32] = &signal.to_samples();
+        let shaping = ToneShaping {
+            transition_us: args.transition_us,
+            ramp_us: args.ramp_ms * 1000.,
+        };
+        let written: &[f32] = &signal.to_samples_shaped(&shaping);
 
-        let spec = WavSpec {
-            channels: 1,
-            sample_rate: SAMPLE_RATE as u32,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+        let output = OutputOptions {
+            level_dbfs: args.level_dbfs,
+            dc_offset: args.dc_offset,
+            format: args.format,
+            layout: args.channels,
         };
 
-        let mut writer = WavWriter::create(args.ouput_file, spec).unwrap();
+        if args.play {
+            let ptt: Option<Result<Box<dyn Ptt>, PttError>> =
+                match (args.ptt_serial.as_deref(), args.rigctld.as_deref()) {
+                    (Some(path), _) => {
+                        Some(SerialPtt::open(path, args.ptt_line).map(|ptt| Box::new(ptt) as _))
+                    }
+                    (_, Some(addr)) => {
+                        Some(RigctldPtt::connect(addr).map(|ptt| Box::new(ptt) as _))
+                    }
+                    _ => None,
+                };
+
+            let play = || {
+                play(
+                    args.host.as_deref(),
+                    args.device.as_deref(),
+                    written,
+                    &output,
+                )
+            };
+
+            match ptt {
+                Some(Ok(mut ptt)) => {
+                    let options = PttOptions {
+                        pre_key_us: args.ptt_pre_ms * 1000.,
+                        post_key_us: args.ptt_post_ms * 1000.,
+                    };
+
+                    if let Err(err) = ptt::with_ptt(ptt.as_mut(), &options, play) {
+                        println!("{err}");
+                    }
+                }
+                Some(Err(err)) => println!("{err}"),
+                None => play(),
+            }
+            return;
+        }
 
-        for elem in written {
-            writer.write_sample(*elem).unwrap();
+        match (args.ouput_file.as_str(), args.raw) {
+            ("-", Some(format)) => audio::write_raw(
+                std::io::stdout().lock(),
+                written,
+                format,
+                args.rate,
+                &output,
+            )
+            .unwrap(),
+            (path, Some(format)) => {
+                let file = BufWriter::new(File::create(path).unwrap());
+                audio::write_raw(file, written, format, args.rate, &output).unwrap()
+            }
+            ("-", None) => {
+                // Stdout can't seek, so the WAV gets built up in memory first
+                let mut wav = Cursor::new(Vec::new());
+                audio::write_wav(&mut wav, written, &output).unwrap();
+                std::io::stdout().write_all(wav.get_ref()).unwrap();
+            }
+            (path, None) => audio::write_wav_file(path, written, &output).unwrap(),
+        }
+    }
+}
+
+/// Run the channel simulator over an audio file
+#[cfg(feature = "cli")]
+fn simulate(args: SimulateArgs) {
+    let samples = match audio::load_file(&args.input_file, ChannelSelect::Mix) {
+        Ok(samples) => samples,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
+
+    let mut paths: Vec<Path> = args
+        .fading
+        .map(|fading| fading.paths().to_vec())
+        .unwrap_or_default();
+    if let Some(echo_ms) = args.echo_ms {
+        // An echo on its own still needs the direct path to be an echo of
+        if paths.is_empty() {
+            paths.push(Path {
+                delay_us: 0.,
+                gain_db: 0.,
+                doppler_spread: 0.,
+            });
+        }
+        paths.push(Path {
+            delay_us: echo_ms * 1000.,
+            gain_db: args.echo_db,
+            doppler_spread: 0.,
+        });
+    }
+
+    let options = ChannelOptions {
+        snr_db: args.snr,
+        paths,
+        freq_offset: args.freq_offset,
+        clock_ppm: args.clock_ppm,
+        tones: args.tone,
+        ssb_filter: args.ssb_filter,
+        seed: args.seed,
+    };
+    let impaired = sim::simulate(&samples, &options);
+
+    let output = OutputOptions {
+        format: args.format,
+        ..OutputOptions::default()
+    };
+    if let Err(err) = audio::write_wav_file(&args.output_file, &impaired, &output) {
+        println!("{err}");
+    }
+}
+
+/// Render the spectrogram of an audio file
+#[cfg(feature = "cli")]
+fn render_spectrogram(args: SpectrogramArgs) {
+    let samples = match audio::load_file(&args.input_file, ChannelSelect::Mix) {
+        Ok(samples) => samples,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
+
+    let markers = if args.no_markers {
+        Vec::new()
+    } else {
+        spectrogram::find_markers(&samples, &DecoderOptions::default())
+    };
+
+    let options = SpectrogramOptions {
+        window_us: args.window_ms * 1000.,
+        step_us: args.step_ms * 1000.,
+        min_freq: args.min_freq,
+        max_freq: args.max_freq,
+        width: args.width,
+        range_db: args.range_db,
+    };
+    let image = spectrogram::render(&samples, &markers, &options);
+
+    if let Err(err) = image.save_with_format(&args.output_file, ImageFormat::Png) {
+        println!("{err}");
+    }
+}
+
+/// Play the signal on the selected output device, printing progress as it goes and
+/// stopping early if enter is pressed
+#[cfg(feature = "cli")]
+fn play(host: Option<&str>, device: Option<&str>, samples: &[f32], output: &OutputOptions) {
+    let device =
+        match device::find_host(host).and_then(|host| device::find_output_device(&host, device)) {
+            Ok(device) => device,
+            Err(err) => {
+                println!("{err}");
+                return;
+            }
+        };
+    println!("using device {:?}", device.name().unwrap_or_default());
+
+    let abort = Arc::new(AtomicBool::new(false));
+    let stdin_abort = abort.clone();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line).is_ok() {
+            stdin_abort.store(true, Ordering::Relaxed);
         }
+    });
+
+    let result = device::play(&device, samples, output, &abort, |played, total| {
+        let secs = |n: usize| n as f64 / SAMPLE_RATE as f64;
+        print!(
+            "\rtransmitting {:5.1}s / {:.1}s ({:3.0}%)",
+            secs(played),
+            secs(total),
+            played as f64 / total.max(1) as f64 * 100.
+        );
+        std::io::stdout().flush().unwrap();
+    });
+    println!();
+
+    match result {
+        Ok(true) => println!("Finished transmitting"),
+        Ok(false) => println!("Transmission aborted"),
+        Err(err) => println!("{err}"),
+    }
+}
 
-        writer.finalize().unwrap();
+/// Open the input file for streaming, `-` or no file being stdin
+#[cfg(feature = "cli")]
+fn open_input(path: Option<&str>) -> Box<dyn Read + Send> {
+    match path {
+        Some("-") | None => Box::new(std::io::stdin()),
+        Some(path) => Box::new(BufReader::new(File::open(path).unwrap())),
     }
 }
 
+/// Call `read_chunk` on another thread until it returns None, sending every chunk
+/// over the returned channel
+#[cfg(feature = "cli")]
+fn spawn_source(
+    mut read_chunk: impl FnMut() -> Option<Vec<f32>> + Send + 'static,
+) -> mpsc::Receiver<Vec<f32>> {
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        while let Some(chunk) = read_chunk() {
+            if tx.send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Print the level of the input, warning if it's clipping or too quiet to decode well
+#[cfg(feature = "cli")]
+fn print_levels(samples: &[f32]) {
+    let levels = LevelMetrics::measure(samples);
+
+    let warning = if levels.clipped > 0 {
+        " - clipping, turn the input down"
+    } else if levels.peak_dbfs() < -40. {
+        " - very quiet, turn the input up"
+    } else {
+        ""
+    };
+
+    println!(
+        "Input level: RMS {:.1}dBFS, peak {:.1}dBFS, {} clipped{}",
+        levels.rms_dbfs(),
+        levels.peak_dbfs(),
+        levels.clipped,
+        warning
+    );
+}
+
+/// Write out the trace files asked for of a decode
+#[cfg(feature = "cli")]
+fn write_trace(args: &Args, track: &[f64], events: &[TraceEvent]) {
+    if let Some(path) = &args.trace_events {
+        let written = File::create(path)
+            .and_then(|file| trace::write_events_csv(BufWriter::new(file), events));
+        if let Err(err) = written {
+            println!("failed to write {path}: {err}");
+        }
+    }
+
+    if let Some(path) = &args.trace_track {
+        let written =
+            File::create(path).and_then(|file| trace::write_track_csv(BufWriter::new(file), track));
+        if let Err(err) = written {
+            println!("failed to write {path}: {err}");
+        }
+    }
+
+    if let Some(path) = &args.trace_plot {
+        let ms_to_sample = |ms: f64| us_to_n_samples(ms * 1000.);
+        let from = ms_to_sample(args.trace_from_ms);
+        let to = args.trace_to_ms.map_or(track.len(), ms_to_sample);
+
+        let plot = trace::render_plot(track, events, from, to, args.trace_width, 400);
+        if let Err(err) = plot.save_with_format(path, ImageFormat::Png) {
+            println!("failed to write {path}: {err}");
+        }
+    }
+}
+
+/// Print the measurements made while decoding an image
+#[cfg(feature = "cli")]
+fn print_info(info: &DecodeInfo) {
+    let quality = if info.line_quality.is_empty() {
+        0.
+    } else {
+        info.line_quality.iter().sum::<f64>() / info.line_quality.len() as f64
+    };
+
+    println!(
+        "{:?}: SNR {:.1}dB, {:.0}% clean syncs, mean line quality {:.2}",
+        info.mode,
+        info.snr_db,
+        info.sync_rate * 100.,
+        quality
+    );
+    println!(
+        "Frequency offset: {:+.0}Hz, slant {:+.0}ppm, samples {}-{}",
+        info.freq_offset, info.slant_ppm, info.start_sample, info.end_sample
+    );
+}
+
+/// Print how decoding got started, from the header or the line syncs
+#[cfg(feature = "cli")]
+fn print_start(info: &DecodeInfo) {
+    match info.header {
+        Some(_) => println!("found header"),
+        None => println!("found line syncs"),
+    }
+}
+
+/// Decode live from chunks of samples sent over `rx`, saving the image every time
+/// a chunk is decoded. Stops once the image is finished or the sender hangs up.
+#[cfg(feature = "cli")]
+fn live_decode(mode: Option<Mode>, options: &DecoderOptions, rx: mpsc::Receiver<Vec<f32>>) {
+    let mut decoder = DetectingDecoder::new(mode, options.clone());
+    let mut started = false;
+    loop {
+        // Main thread logic:
+        let mut buf = Vec::new();
+        let mut ended = false;
+
+        // Get data from streaming thread, accumulating into a bigger vec of at least 100k samples
+        // this makes the live decode not be the bottleneck in real time decodes, as it doesn't
+        // have to do the DSP processing as many times which takes roughly 100ms (which happens
+        // every time we call the decode fn) (filtering, quadrature demod)
+        // This adds up fast when sending buffers of just 512 samples.
+        // TODO: make it faster and send samples directly without accumulating
+        while buf.len() <= 100_000 {
+            match rx.recv() {
+                Ok(mut received) => buf.append(&mut received),
+                Err(_) => {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+
+        print_levels(&buf);
+        let decode = decoder.decode(&buf);
+
+        if let DecodeResult::Finished(_, ref info) | DecodeResult::Partial(_, ref info) = decode
+            && !started
+        {
+            if mode.is_none() {
+                println!("Detected {:?}", info.mode);
+            }
+            print_start(info);
+            started = true;
+        }
+
+        // Save image every time we call decode
+        if let DecodeResult::Partial(ref image, _) = decode {
+            image.save_with_format("out.png", ImageFormat::Png).unwrap();
+        }
+        if let DecodeResult::Finished(ref image, ref info) = decode {
+            image.save_with_format("out.png", ImageFormat::Png).unwrap();
+            print_info(info);
+            break;
+        }
+
+        if ended {
+            break;
+        }
+    }
+
+    println!("Finished decoding");
+}
+
 // Here to stop the rust compiler complaining that there is no main function with wasm target
 #[cfg(not(feature = "cli"))]
 fn main() {}
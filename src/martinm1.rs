@@ -1,12 +1,42 @@
-use biquad::{Biquad, Coefficients, DirectForm1, ToHertz, Type};
 use image::{ColorType, DynamicImage, GenericImage, GenericImageView, Pixel, imageops::FilterType};
 
 use crate::{
-    SAMPLE_RATE,
-    common::{DSPOut, DecodeResult, SSTVMode, Signal},
+    common::{
+        DSPOut, DecodeInfo, DecodeResult, DecoderOptions, EncoderOptions, LineSync, Mode, SSTVMode,
+        Scan, Signal, find_line_syncs, get_calibration_header, us_to_samples,
+    },
     dsp,
 };
 
+/// Length of a whole line in μs: the sync, 4 colour separators and 3 colour scanlines
+pub(crate) const LINE_US: f64 = 4862. + 4. * 572. + 3. * 320. * 457.6;
+
+/// How far before a sync found by `find_line_syncs` decoding starts from in μs, so the
+/// sync is waited for as normal
+const SYNC_LEAD_US: f64 = 2000.;
+
+/// The green, blue and red scans in each line, each after a separator
+pub(crate) const SCANS: [Scan; 3] = [
+    Scan {
+        start_us: 4862. + 572.,
+        pixels: 320,
+        pixel_us: 457.6,
+        channel: Some(1),
+    },
+    Scan {
+        start_us: 4862. + 2. * 572. + 320. * 457.6,
+        pixels: 320,
+        pixel_us: 457.6,
+        channel: Some(2),
+    },
+    Scan {
+        start_us: 4862. + 3. * 572. + 2. * 320. * 457.6,
+        pixels: 320,
+        pixel_us: 457.6,
+        channel: Some(0),
+    },
+];
+
 /// A struct implementing the Martin M1 SSTV mode
 ///
 /// eg:
@@ -27,51 +57,40 @@ pub struct MartinM1 {
     /// to this vec
     samples: Vec<f32>,
 
+    /// How the signal is processed before decoding
+    options: DecoderOptions,
+    /// Measurements of the signal being decoded
+    info: DecodeInfo,
+
     // Used for caching in live decodes
     in_partial_decode: bool,
-    pos: usize,
+    pos: f64,
     row: u32,
 }
 
 // Documentation for `MartinM1::encode` and `MartinM1::decode` can be found in the SSTVMode trait
 
 impl SSTVMode for MartinM1 {
-    fn new() -> Self {
+    fn with_options(options: DecoderOptions) -> Self {
         MartinM1 {
+            options,
+            info: DecodeInfo::new(Mode::MartinM1),
             decoded_image: DynamicImage::new(320, 256, ColorType::Rgb16),
             samples: Vec::new(),
             in_partial_decode: false,
             row: 0,
-            pos: 0,
+            pos: 0.,
         }
     }
 
-    fn encode(&mut self, image: image::DynamicImage) -> Signal {
+    fn encode_with_options(
+        &mut self,
+        image: image::DynamicImage,
+        options: &EncoderOptions,
+    ) -> Signal {
         let resize = image.resize_exact(320, 256, FilterType::Nearest);
         let mut out = Signal::new();
-        out.push(1000, 200_000.);
-        // Start header
-        // Comprised of a 1900Hz 300ms leader tone, followed by a 1200Hz 10ms break and another leader
-        out.push(1900, 300_000.);
-        out.push(1200, 10_000.);
-        out.push(1900, 300_000.);
-
-        // 0b1011001 VIS code
-        // 30ms 1200Hz leader followed by 7 30ms long bits - 1100Hz for a 1 and 1300Hz for a 0
-
-        // start bit
-        out.push(1200, 30_000.);
-
-        out.push(1100, 30_000.);
-        out.push(1300, 30_000.);
-        out.push(1300, 30_000.);
-        out.push(1100, 30_000.);
-        out.push(1100, 30_000.);
-        out.push(1300, 30_000.);
-        out.push(1100, 30_000.);
-
-        // stop bit
-        out.push(1200, 30_000.);
+        out.push_calibration_header(Mode::MartinM1, options);
 
         // Loop through rows in the image
         for i in 0..256 {
@@ -79,23 +98,13 @@ impl SSTVMode for MartinM1 {
             sync(&mut out);
             colour_sep(&mut out);
 
-            // Do colour channels in order BGR rather than RGB
+            // Do colour channels in order GBR rather than RGB
             for colour in [1, 2, 0] {
-                // Go through the scanline
-                for j in 0..320 {
-                    // Grab the pixels value corresponding to the correct colour channel
-                    let pixel = resize.get_pixel(j, i);
-                    let rgb = pixel.to_rgb();
-                    let channels = rgb.channels();
-                    // Calculate a % luminance for said colour, this being multiplied with the total modulating
-                    // range to figure out frequency
-                    let value = channels[colour] as f64 / u8::MAX as f64;
+                // Go through the scanline, grabbing the value of the correct colour channel
+                // for each pixel, each colour being 457.6μs long
+                let values = (0..320).map(|j| resize.get_pixel(j, i).to_rgb().channels()[colour]);
+                out.push_scanline(values, 457.6);
 
-                    // Calculating modulating frequency in a range between 2300 and 1500Hz, each colour being 457.6μs long
-                    let range = 2300. - 1500.;
-                    let freq = value * range;
-                    out.push(freq as usize + 1500, 457.6);
-                }
                 colour_sep(&mut out);
             }
         }
@@ -108,58 +117,50 @@ impl SSTVMode for MartinM1 {
         // Accumulate next chunk of samples into internal buffer
         self.samples.append(&mut audio.to_vec());
 
-        // IIR Bandpass filter, 1KHz to 3KHz passband
-        // TODO: some form of caching to speedup live decodes
-        // as self.samples grows from the stream from the microphone
-        // the filter will have to recalculate across every sample every time a
-        // live decode is requested, hindering lower buffer sizes
-
-        let fl = 1.khz();
-        let fh = 3.khz();
-        let fs = SAMPLE_RATE.hz();
-
-        let coeffs_lp = Coefficients::<f64>::from_params(Type::LowPass, fs, fh, 1.).unwrap();
-        let coeffs_hp = Coefficients::<f64>::from_params(Type::HighPass, fs, fl, 1.).unwrap();
-
-        let mut biquad_lp = DirectForm1::<f64>::new(coeffs_lp);
-        let mut biquad_hp = DirectForm1::<f64>::new(coeffs_hp);
-
-        // TODO: Have less allocations here. (Vec::with_capacity will take ages across the millions of samples
-        // it grows to)
-        // Would likely speed it up tenfold
-
-        let mut filtered = Vec::with_capacity(audio.len());
-
-        for elem in &self.samples {
-            filtered.push(biquad_lp.run(*elem as f64));
-        }
-
-        for elem in filtered.iter_mut() {
-            *elem = biquad_hp.run(*elem);
-        }
+        // Filter and demodulate the whole buffer
+        let filtered_dsp = dsp::demodulate(&self.samples, &self.options);
+        self.read(&filtered_dsp)
+    }
 
-        // Perform a quadrature demodulation on the filtered signal
-        let res = dsp::quadrature_demod(&filtered);
+    fn decode_track(&mut self, audio: &[f32], track: &[f64]) -> DecodeResult {
+        // Accumulate next chunk of samples into internal buffer
+        self.samples.append(&mut audio.to_vec());
+        self.read(track)
+    }
 
-        let mut filtered_dsp = Vec::with_capacity(res.len());
-        let coeffs_lpd = Coefficients::<f64>::from_params(Type::LowPass, fs, 400.hz(), 1.).unwrap();
-        let mut biquad_dsp_out = DirectForm1::<f64>::new(coeffs_lpd);
-        for elem in res {
-            filtered_dsp.push(biquad_dsp_out.run(elem));
-        }
-        //plot!("sda", &filtered_dsp);
+    fn get_image(&self) -> image::DynamicImage {
+        self.decoded_image.clone()
+    }
+}
 
-        let mut out = DSPOut::new(&filtered_dsp);
+impl MartinM1 {
+    /// Decode from the demodulated `track` of every sample so far, carrying on from where
+    /// the last decode left off
+    fn read(&mut self, track: &[f64]) -> DecodeResult {
+        let mut out = DSPOut::new(track);
 
         // Set the position of the cursor over the samples to the spot the last decode ended at
         out.set_to(self.pos);
+        out.set_freq_offset(self.info.freq_offset);
+        if self.options.trace {
+            out.trace();
+        }
 
         // If not in a partial decode, look for the header, exiting if no header is found
         if !self.in_partial_decode {
-            if let None = self.get_calibration_header(&mut out) {
+            if let Some(header) = get_calibration_header(&mut out) {
+                self.info.freq_offset = out.get_freq_offset();
+                self.info.header = Some(header);
+                self.info.start_sample = header.start;
+            } else if let Some(sync) = self.find_line_syncs(&mut out) {
+                // Joined partway through. There's no telling which row this line was sent
+                // as, so start filling the image from the top
+                self.info.freq_offset = sync.freq_offset;
+                self.info.start_sample = sync.start as usize;
+            } else {
                 return DecodeResult::NoneFound;
             }
-            println!("found header");
+            self.info.events.extend(out.take_events());
         }
 
         // Loop through every row, starting from the last decoded row
@@ -168,106 +169,64 @@ impl SSTVMode for MartinM1 {
             let start_pos = out.get_pos();
 
             // If the buffer of samples ends...
-            if let None = out
-                .take_till_frq(1200.)
-                .and_then(|_| out.take_while_frq(1200.))
-                .and_then(|_| out.take_us(572.))
-            {
+            let Some(sync) = out
+                .take_sync(1200., 4862.)
+                .and_then(|sync| out.take_us(572.).map(|_| sync))
+            else {
                 // Save the position over the buffer & retain information about position, returning the image
                 self.pos = start_pos;
                 self.in_partial_decode = true;
                 self.row = i;
                 // TODO: remove allocation here
-                return DecodeResult::Partial(self.decoded_image.clone());
-            }
+                return DecodeResult::Partial(self.decoded_image.clone(), self.info.clone());
+            };
             // Loop through every colour channel..
             for colour in [1, 2, 0] {
-                // And scanline
-                for j in 0..320 {
-                    // Try take a pixels worth of data, saving if it fails
-                    if let Some(val) = out.take_us(457.6) {
-                        // Calculate brightness based off of the average freq over the 457.6μs
-                        let brightness = (val - 1500.) / (2300. - 1500.);
- 
-                        let mut rgb = self.decoded_image.get_pixel(j, i);
-
-                        rgb.channels_mut()[colour] = (brightness * 255.) as u8;
-                        // Put colour value back to the image
-                        self.decoded_image.put_pixel(j, i, rgb);
-                    } else {
-                        // If we run out, save data
-                        self.pos = start_pos;
-                        self.row = i;
-                        self.in_partial_decode = true;
-                        return DecodeResult::Partial(self.decoded_image.clone());
-                    }
-                }
-
-                // Try take the colour seperator mark, saving if it fails
-                if let None = out.take_us(572.) {
+                // Try take a scanline and the colour seperator mark, saving data if it fails
+                let Some(values) = out
+                    .take_scanline(320, 457.6)
+                    .and_then(|values| out.take_us(572.).map(|_| values))
+                else {
                     self.pos = start_pos;
                     self.row = i;
                     self.in_partial_decode = true;
-                    return DecodeResult::Partial(self.decoded_image.clone());
+                    return DecodeResult::Partial(self.decoded_image.clone(), self.info.clone());
+                };
+
+                for (j, value) in values.into_iter().enumerate() {
+                    let mut rgb = self.decoded_image.get_pixel(j as u32, i);
+
+                    rgb.channels_mut()[colour] = value;
+                    // Put colour value back to the image
+                    self.decoded_image.put_pixel(j as u32, i, rgb);
                 }
             }
+
+            let quality = self
+                .info
+                .add_line(&self.samples, i as usize, &sync, 1200., LINE_US);
+            self.info.line_quality.push(quality);
+            self.info.end_sample = out.get_pos() as usize;
+            self.info.events.extend(out.take_events());
         }
 
         // If we get through that loop, we successfully decoded the image!
-        DecodeResult::Finished(self.decoded_image.clone())
+        DecodeResult::Finished(self.decoded_image.clone(), self.info.clone())
     }
 
-    fn get_image(&self) -> image::DynamicImage {
-        self.decoded_image.clone()
-    }
-}
-
-impl MartinM1 {
-    /// This function looks for the calibration header of the samples, returning
-    /// the 8 bit VIS code if one is found.
-    ///
-    /// TODO: Generalise to all SSTV modes & remove the self argument.
-    fn get_calibration_header(&mut self, sig: &mut DSPOut) -> Option<u8> {
-        sig.take_till_frq(1900.)?;
-
-        sig.take_while_frq_within(1900., 400.)?;
-
-        sig.take_till_frq(1200.)?;
-
-        let avg = sig.take_us(300_000.)?;
-
-        if (avg - 1900.).abs() > 200. {
+    /// Look for a run of Martin M1 line syncs if decoding without a header, moving `out` to
+    /// just before the first one
+    fn find_line_syncs(&self, out: &mut DSPOut) -> Option<LineSync> {
+        if !self.options.headerless {
             return None;
         }
 
-        let vis = [
-            sig.take_us(30_000.)? < 1200.,
-            sig.take_us(30_000.)? < 1200.,
-            sig.take_us(30_000.)? < 1200.,
-            sig.take_us(30_000.)? < 1200.,
-            sig.take_us(30_000.)? < 1200.,
-            sig.take_us(30_000.)? < 1200.,
-        ];
-
-        let _parity = sig.take_us(30_000.)? < 1200.;
-
-        // even parity bit check
-        //assert!(
-        //    vis.iter().map(|b| *b as u8).sum::<u8>() % 2 != parity as u8,
-        //    "parity bit failed"
-        //);
-
-        sig.take_while_frq(1200.)?;
-
-        let mut total = 0;
-
-        for (pos, elem) in vis.iter().enumerate() {
-            total += 2_u8.pow((6 - pos) as u32) * (*elem as u8);
-        }
-
-        //println!("header found");
-
-        Some(total)
+        out.set_to(self.pos);
+        out.set_freq_offset(0.);
+        let sync = find_line_syncs(out, &[Mode::MartinM1])?;
+        out.set_to((sync.start - us_to_samples(SYNC_LEAD_US)).max(self.pos));
+        out.set_freq_offset(sync.freq_offset);
+        Some(sync)
     }
 }
 
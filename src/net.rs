@@ -0,0 +1,127 @@
+use std::io::{self, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Where network audio comes from
+#[derive(Clone, Debug)]
+pub enum NetSource {
+    /// Connect to a server streaming audio over TCP
+    TcpConnect(String),
+    /// Listen for a single TCP connection to stream audio in
+    TcpListen(String),
+    /// Receive audio as UDP datagrams sent to this address, like GQRX and SDR++ send
+    Udp(String),
+}
+
+impl NetSource {
+    /// Open the source as a stream of raw PCM bytes, ready for `audio::RawReader`.
+    ///
+    /// Listening for a TCP connection blocks until someone connects.
+    pub fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            NetSource::TcpConnect(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+            NetSource::TcpListen(addr) => {
+                let (stream, _) = TcpListener::bind(addr)?.accept()?;
+                Ok(Box::new(stream))
+            }
+            NetSource::Udp(addr) => Ok(Box::new(UdpStream::new(UdpSocket::bind(addr)?))),
+        }
+    }
+}
+
+/// Largest datagram we expect to receive
+const MAX_DATAGRAM: usize = 65536;
+
+/// Reads the payloads of UDP datagrams one after another as a stream of bytes.
+///
+/// Lost or reordered datagrams aren't detected, they just show up as glitches in the audio.
+pub struct UdpStream {
+    socket: UdpSocket,
+    datagram: Vec<u8>,
+    /// Position of the next unread byte of `datagram`
+    pos: usize,
+}
+
+impl UdpStream {
+    pub fn new(socket: UdpSocket) -> UdpStream {
+        UdpStream {
+            socket,
+            datagram: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for UdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Skip over empty datagrams, a read of 0 bytes would look like the end of the stream
+        while self.pos == self.datagram.len() {
+            self.datagram.resize(MAX_DATAGRAM, 0);
+            let len = self.socket.recv(&mut self.datagram)?;
+            self.datagram.truncate(len);
+            self.pos = 0;
+        }
+
+        let len = buf.len().min(self.datagram.len() - self.pos);
+        buf[..len].copy_from_slice(&self.datagram[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        SAMPLE_RATE,
+        audio::{RawFormat, RawReader},
+    };
+
+    /// A `UdpStream` on localhost and a socket sending to it
+    fn udp_pair() -> (UdpStream, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Fail rather than hang if a datagram goes missing
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(socket.local_addr().unwrap()).unwrap();
+
+        (UdpStream::new(socket), sender)
+    }
+
+    #[test]
+    fn datagram_read_in_pieces() {
+        let (mut stream, sender) = udp_pair();
+        sender.send(&[1, 2, 3, 4, 5]).unwrap();
+        sender.send(&[6]).unwrap();
+
+        let mut buf = [0; 2];
+        let reads: Vec<Vec<u8>> = (0..4)
+            .map(|_| {
+                let len = stream.read(&mut buf).unwrap();
+                buf[..len].to_vec()
+            })
+            .collect();
+        assert_eq!(reads, [vec![1, 2], vec![3, 4], vec![5], vec![6]]);
+    }
+
+    #[test]
+    fn samples_split_across_datagrams() {
+        let (stream, sender) = udp_pair();
+        // 0.5, -0.5 and 0.25 as s16le, split unevenly with an empty datagram between
+        sender.send(&[0x00, 0x40, 0x00]).unwrap();
+        sender.send(&[]).unwrap();
+        sender.send(&[0xc0, 0x00]).unwrap();
+        sender.send(&[0x20]).unwrap();
+
+        let mut reader = RawReader::new(stream, RawFormat::S16le, SAMPLE_RATE as u32);
+        let mut samples = Vec::new();
+        while samples.len() < 3 {
+            samples.extend(reader.read_chunk(8192).unwrap().unwrap());
+        }
+        assert_eq!(samples, [0.5, -0.5, 0.25]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+/// An error while keying or unkeying the radio
+#[derive(Debug)]
+pub enum PttError {
+    Io(std::io::Error),
+    Serial(serialport::Error),
+    /// rigctld answered with something other than `RPRT 0`
+    Rig(String),
+}
+
+impl fmt::Display for PttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PttError::Io(err) => write!(f, "PTT IO error: {err}"),
+            PttError::Serial(err) => write!(f, "PTT serial port error: {err}"),
+            PttError::Rig(reply) => write!(f, "rigctld refused PTT: {reply:?}"),
+        }
+    }
+}
+
+impl std::error::Error for PttError {}
+
+impl From<std::io::Error> for PttError {
+    fn from(err: std::io::Error) -> Self {
+        PttError::Io(err)
+    }
+}
+
+impl From<serialport::Error> for PttError {
+    fn from(err: serialport::Error) -> Self {
+        PttError::Serial(err)
+    }
+}
+
+/// Something that can key a radio's transmitter
+pub trait Ptt {
+    /// Key the transmitter if `on`, otherwise unkey it
+    fn key(&mut self, on: bool) -> Result<(), PttError>;
+}
+
+/// Serial control line used to key the radio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SerialLine {
+    Rts,
+    Dtr,
+}
+
+/// PTT through the RTS or DTR line of a serial port, as used by most interface cables
+pub struct SerialPtt {
+    port: Box<dyn SerialPort>,
+    line: SerialLine,
+}
+
+impl SerialPtt {
+    /// Open a serial port (`/dev/ttyUSB0`, `COM3`...), starting unkeyed
+    pub fn open(path: &str, line: SerialLine) -> Result<SerialPtt, PttError> {
+        let port = serialport::new(path, 9600)
+            .timeout(Duration::from_secs(1))
+            .open()?;
+
+        let mut ptt = SerialPtt { port, line };
+        ptt.key(false)?;
+        Ok(ptt)
+    }
+}
+
+impl Ptt for SerialPtt {
+    fn key(&mut self, on: bool) -> Result<(), PttError> {
+        match self.line {
+            SerialLine::Rts => self.port.write_request_to_send(on)?,
+            SerialLine::Dtr => self.port.write_data_terminal_ready(on)?,
+        }
+        Ok(())
+    }
+}
+
+/// PTT through Hamlib's `rigctld` daemon, using its text protocol over TCP
+pub struct RigctldPtt {
+    stream: BufReader<TcpStream>,
+}
+
+impl RigctldPtt {
+    /// Connect to rigctld, usually listening on `localhost:4532`
+    pub fn connect(addr: &str) -> Result<RigctldPtt, PttError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        Ok(RigctldPtt {
+            stream: BufReader::new(stream),
+        })
+    }
+}
+
+impl Ptt for RigctldPtt {
+    fn key(&mut self, on: bool) -> Result<(), PttError> {
+        writeln!(self.stream.get_mut(), "T {}", on as u8)?;
+
+        // Set commands are answered with `RPRT 0` on success, or a negative error code
+        let mut reply = String::new();
+        self.stream.read_line(&mut reply)?;
+
+        match reply.trim() {
+            "RPRT 0" => Ok(()),
+            reply => Err(PttError::Rig(reply.to_string())),
+        }
+    }
+}
+
+/// Delays around a transmission
+#[derive(Clone, Debug)]
+pub struct PttOptions {
+    /// Time between keying and sending audio in μs, letting the radio switch over
+    pub pre_key_us: f64,
+    /// Time between the audio ending and unkeying in μs, so the end isn't cut off
+    pub post_key_us: f64,
+}
+
+impl Default for PttOptions {
+    fn default() -> Self {
+        PttOptions {
+            pre_key_us: 200_000.,
+            post_key_us: 200_000.,
+        }
+    }
+}
+
+/// Key the radio, run `transmit`, then unkey it again with the delays from `options`.
+///
+/// The radio is unkeyed even if `transmit` panics, so it isn't left transmitting.
+pub fn with_ptt<T>(
+    ptt: &mut dyn Ptt,
+    options: &PttOptions,
+    transmit: impl FnOnce() -> T,
+) -> Result<T, PttError> {
+    ptt.key(true)?;
+    let keyed = Keyed(Some(ptt));
+    std::thread::sleep(Duration::from_secs_f64(options.pre_key_us / 1e6));
+
+    let result = transmit();
+
+    std::thread::sleep(Duration::from_secs_f64(options.post_key_us / 1e6));
+    keyed.unkey()?;
+
+    Ok(result)
+}
+
+/// A keyed radio, unkeyed when dropped if `unkey` wasn't called first
+struct Keyed<'a>(Option<&'a mut dyn Ptt>);
+
+impl Keyed<'_> {
+    /// Unkey the radio, passing on any error
+    fn unkey(mut self) -> Result<(), PttError> {
+        match self.0.take() {
+            Some(ptt) => ptt.key(false),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Keyed<'_> {
+    fn drop(&mut self) {
+        // Only reached without `unkey` when unwinding, where there's nothing to do with
+        // an error but carry on
+        if let Some(ptt) = self.0.take() {
+            let _ = ptt.key(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    /// Serve a single connection like rigctld, answering each command with the next of
+    /// `replies`. Returns the address to connect to and the commands received.
+    fn mock_rigctld(replies: &'static [&'static str]) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut commands = Vec::new();
+
+            for reply in replies {
+                let mut command = String::new();
+                if stream.read_line(&mut command).unwrap() == 0 {
+                    break;
+                }
+                commands.push(command.trim().to_string());
+                writeln!(stream.get_mut(), "{reply}").unwrap();
+            }
+            commands
+        });
+
+        (addr, handle)
+    }
+
+    /// Records what it's asked to key
+    #[derive(Default)]
+    struct MockPtt(Vec<bool>);
+
+    impl Ptt for MockPtt {
+        fn key(&mut self, on: bool) -> Result<(), PttError> {
+            self.0.push(on);
+            Ok(())
+        }
+    }
+
+    const NO_DELAY: PttOptions = PttOptions {
+        pre_key_us: 0.,
+        post_key_us: 0.,
+    };
+
+    #[test]
+    fn rigctld_keys_and_unkeys() {
+        let (addr, rigctld) = mock_rigctld(&["RPRT 0", "RPRT 0"]);
+        let mut ptt = RigctldPtt::connect(&addr).unwrap();
+
+        assert_eq!(with_ptt(&mut ptt, &NO_DELAY, || 1).unwrap(), 1);
+        drop(ptt);
+
+        assert_eq!(rigctld.join().unwrap(), ["T 1", "T 0"]);
+    }
+
+    #[test]
+    fn rigctld_refusal_is_an_error() {
+        let (addr, rigctld) = mock_rigctld(&["RPRT -1"]);
+        let mut ptt = RigctldPtt::connect(&addr).unwrap();
+
+        match ptt.key(true) {
+            Err(PttError::Rig(reply)) => assert_eq!(reply, "RPRT -1"),
+            other => panic!("expected the rig to refuse, got {other:?}"),
+        }
+        drop(ptt);
+
+        assert_eq!(rigctld.join().unwrap(), ["T 1"]);
+    }
+
+    #[test]
+    fn unkeys_after_panic() {
+        let mut ptt = MockPtt::default();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            with_ptt(&mut ptt, &NO_DELAY, || panic!("playback failed"))
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(ptt.0, [true, false]);
+    }
+}
@@ -0,0 +1,415 @@
+use core::f64;
+use std::str::FromStr;
+
+use biquad::{Biquad, Coefficients, DirectForm1, Type};
+use num_complex::Complex64;
+
+use crate::{SAMPLE_RATE, common::us_to_n_samples, dsp::Resampler};
+
+/// Bandwidth the SNR is measured over in Hz, the usual bandwidth of an SSB receiver
+pub const SNR_BANDWIDTH: f64 = 3000.;
+
+/// Number of sinusoids summed to make the gain of a fading path. Enough for the
+/// amplitude to come out Rayleigh distributed.
+const FADING_SINUSOIDS: usize = 32;
+
+/// One of the paths a signal takes to the receiver, like a single hop off the ionosphere
+#[derive(Clone, Copy, Debug)]
+pub struct Path {
+    /// How long after the signal is sent it arrives in μs
+    pub delay_us: f64,
+    /// Average strength of the path in dB
+    pub gain_db: f64,
+    /// How fast the path fades in Hz, twice the standard deviation of its Gaussian doppler
+    /// spectrum. 0 is a steady path that doesn't fade
+    pub doppler_spread: f64,
+}
+
+/// The standard HF channels from ITU-R F.1487, each being two equally strong paths
+/// fading independently on the Watterson model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Fading {
+    /// Quiet mid-latitude conditions, 0.5ms delay and 0.1Hz spread
+    Good,
+    /// Typical mid-latitude conditions, 1ms delay and 0.5Hz spread
+    Moderate,
+    /// Disturbed mid-latitude conditions, 2ms delay and 1Hz spread
+    Poor,
+    /// High latitude flutter fading, 0.5ms delay and 10Hz spread
+    Flutter,
+}
+
+impl Fading {
+    /// The paths making up the channel
+    pub fn paths(&self) -> [Path; 2] {
+        let (delay_us, doppler_spread) = match self {
+            Fading::Good => (500., 0.1),
+            Fading::Moderate => (1000., 0.5),
+            Fading::Poor => (2000., 1.),
+            Fading::Flutter => (500., 10.),
+        };
+
+        [
+            Path {
+                delay_us: 0.,
+                gain_db: 0.,
+                doppler_spread,
+            },
+            Path {
+                delay_us,
+                gain_db: 0.,
+                doppler_spread,
+            },
+        ]
+    }
+}
+
+/// An interfering carrier added to the signal
+#[derive(Clone, Copy, Debug)]
+pub struct Tone {
+    /// Frequency of the tone in Hz
+    pub freq: f64,
+    /// Power of the tone relative to the signal in dB
+    pub level_db: f64,
+}
+
+impl FromStr for Tone {
+    type Err = String;
+
+    /// Parse a tone written as `freq:level`, like `1500:-10`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (freq, level) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected a tone like 1500:-10, got {s:?}"))?;
+
+        Ok(Tone {
+            freq: freq
+                .parse()
+                .map_err(|_| format!("invalid frequency {freq:?}"))?,
+            level_db: level
+                .parse()
+                .map_err(|_| format!("invalid level {level:?}"))?,
+        })
+    }
+}
+
+/// Impairments applied by the channel simulator. The default passes the signal
+/// through untouched.
+#[derive(Clone, Debug)]
+pub struct ChannelOptions {
+    /// Add white gaussian noise, at this signal to noise ratio in dB measured over
+    /// `SNR_BANDWIDTH`
+    pub snr_db: Option<f64>,
+    /// Paths the signal takes, each delayed, scaled and faded then added together.
+    /// Empty passes the signal straight through
+    pub paths: Vec<Path>,
+    /// How far the signal is mistuned in Hz
+    pub freq_offset: f64,
+    /// How much faster the receiver's sample clock runs than the sender's in parts per
+    /// million, stretching the signal out
+    pub clock_ppm: f64,
+    /// Interfering tones to add
+    pub tones: Vec<Tone>,
+    /// Pass the result through a 300-2700Hz filter like an SSB receiver's
+    pub ssb_filter: bool,
+    /// Seed for the noise and fading, so the same options always give the same result
+    pub seed: u64,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        ChannelOptions {
+            snr_db: None,
+            paths: Vec::new(),
+            freq_offset: 0.,
+            clock_ppm: 0.,
+            tones: Vec::new(),
+            ssb_filter: false,
+            seed: 0,
+        }
+    }
+}
+
+/// Pass `samples`, such as those from `Signal::to_samples`, through a simulated radio
+/// channel for testing how well signals decode.
+///
+/// The sample clock is skewed first, as the sound card sending the signal would, then it
+/// goes through the paths and gets mistuned, then the noise and tones are added and
+/// finally the receiver's filter is applied. The result is scaled down if it would clip.
+pub fn simulate(samples: &[f32], options: &ChannelOptions) -> Vec<f32> {
+    let mut rng = Rng::new(options.seed);
+
+    let skewed = if options.clock_ppm != 0. {
+        Resampler::with_ratio(1. + options.clock_ppm * 1e-6).process(samples)
+    } else {
+        samples.to_vec()
+    };
+    let mut signal: Vec<f64> = skewed.iter().map(|sample| *sample as f64).collect();
+
+    if !options.paths.is_empty() || options.freq_offset != 0. {
+        signal = propagate(&signal, options, &mut rng);
+    }
+
+    // Everything added is relative to the power of the signal as it arrives
+    let power = signal.iter().map(|s| s * s).sum::<f64>() / signal.len().max(1) as f64;
+
+    for tone in &options.tones {
+        let amplitude = (2. * power * 10_f64.powf(tone.level_db / 10.)).sqrt();
+        let omega = f64::consts::TAU * tone.freq / SAMPLE_RATE as f64;
+        let phase = rng.uniform() * f64::consts::TAU;
+
+        for (i, sample) in signal.iter_mut().enumerate() {
+            *sample += amplitude * (omega * i as f64 + phase).sin();
+        }
+    }
+
+    if let Some(snr_db) = options.snr_db {
+        // White noise is spread over the whole band, so scale it up from the power that
+        // should land in the measurement bandwidth
+        let in_band = power / 10_f64.powf(snr_db / 10.);
+        let sigma = (in_band * (SAMPLE_RATE as f64 / 2.) / SNR_BANDWIDTH).sqrt();
+
+        for sample in signal.iter_mut() {
+            *sample += sigma * rng.gaussian();
+        }
+    }
+
+    if options.ssb_filter {
+        let mut filters = [
+            butterworth(Type::HighPass, 300.),
+            butterworth(Type::LowPass, 2700.),
+        ]
+        .concat();
+
+        for sample in signal.iter_mut() {
+            *sample = filters
+                .iter_mut()
+                .fold(*sample, |sample, filter| filter.run(sample));
+        }
+    }
+
+    let peak = signal.iter().fold(0., |peak: f64, s| peak.max(s.abs()));
+    let scale = if peak > 1. { 1. / peak } else { 1. };
+
+    signal.iter().map(|s| (s * scale) as f32).collect()
+}
+
+/// Send the signal down every path and add them back together, shifting the frequency by
+/// `freq_offset` on the way. Done on the analytic signal so the fading and shift only
+/// affect positive frequencies, like they would at RF.
+fn propagate(signal: &[f64], options: &ChannelOptions, rng: &mut Rng) -> Vec<f64> {
+    let analytic = hilbert_transform::hilbert(signal);
+
+    let direct = [Path {
+        delay_us: 0.,
+        gain_db: 0.,
+        doppler_spread: 0.,
+    }];
+    let paths = if options.paths.is_empty() {
+        &direct[..]
+    } else {
+        &options.paths[..]
+    };
+
+    let mut received = vec![Complex64::ZERO; analytic.len()];
+    for path in paths {
+        let delay = us_to_n_samples(path.delay_us);
+        let mut gain = PathGain::new(path, rng);
+
+        for (i, out) in received.iter_mut().enumerate().skip(delay) {
+            *out += analytic[i - delay] * gain.next();
+        }
+    }
+
+    let omega = f64::consts::TAU * options.freq_offset / SAMPLE_RATE as f64;
+    received
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| (sample * Complex64::from_polar(1., omega * i as f64)).re)
+        .collect()
+}
+
+/// The changing complex gain of a path, a sum of sinusoids at random doppler shifts
+/// drawn from a Gaussian. This has the Gaussian doppler spectrum of the Watterson model
+/// and a Rayleigh distributed amplitude.
+struct PathGain {
+    phasors: Vec<Complex64>,
+    steps: Vec<Complex64>,
+}
+
+impl PathGain {
+    fn new(path: &Path, rng: &mut Rng) -> PathGain {
+        let amplitude = 10_f64.powf(path.gain_db / 20.);
+
+        // A steady path is a single phasor that never turns
+        let n = if path.doppler_spread > 0. {
+            FADING_SINUSOIDS
+        } else {
+            1
+        };
+        let scale = amplitude / (n as f64).sqrt();
+
+        let (phasors, steps) = (0..n)
+            .map(|_| {
+                let doppler = rng.gaussian() * path.doppler_spread / 2.;
+                let phase = if n == 1 {
+                    0.
+                } else {
+                    rng.uniform() * f64::consts::TAU
+                };
+
+                (
+                    Complex64::from_polar(scale, phase),
+                    Complex64::from_polar(1., f64::consts::TAU * doppler / SAMPLE_RATE as f64),
+                )
+            })
+            .unzip();
+
+        PathGain { phasors, steps }
+    }
+
+    fn next(&mut self) -> Complex64 {
+        let gain = self.phasors.iter().sum();
+        for (phasor, step) in self.phasors.iter_mut().zip(&self.steps) {
+            *phasor *= step;
+        }
+        gain
+    }
+}
+
+/// A 4th order Butterworth filter at `f0` Hz, as two biquads
+fn butterworth(filter: Type<f64>, f0: f64) -> [DirectForm1<f64>; 2] {
+    let normalized = 2. * f0 / SAMPLE_RATE as f64;
+    [0.5412, 1.3066].map(|q| {
+        DirectForm1::new(Coefficients::from_normalized_params(filter, normalized, q).unwrap())
+    })
+}
+
+/// Small xorshift random number generator, so simulations are repeatable from a seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck on 0, and nearby seeds should still give different noise
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// Uniform between 0 and 1
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    /// Gaussian with a standard deviation of 1, by the Box-Muller transform
+    fn gaussian(&mut self) -> f64 {
+        let u = 1. - self.uniform();
+        let v = self.uniform();
+        (-2. * u.ln()).sqrt() * (f64::consts::TAU * v).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustfft::FftPlanner;
+
+    use super::*;
+
+    /// A `freq` Hz tone with a peak of 0.1, `seconds` long
+    fn tone(freq: f64, seconds: usize) -> Vec<f32> {
+        let omega = f64::consts::TAU * freq / SAMPLE_RATE as f64;
+        (0..SAMPLE_RATE * seconds)
+            .map(|i| (0.1 * (omega * i as f64).sin()) as f32)
+            .collect()
+    }
+
+    /// Power of `samples` between `low` and `high` Hz
+    fn band_power(samples: &[f64], low: f64, high: f64) -> f64 {
+        let mut spectrum: Vec<Complex64> = samples.iter().map(|&s| Complex64::new(s, 0.)).collect();
+        FftPlanner::new()
+            .plan_fft_forward(spectrum.len())
+            .process(&mut spectrum);
+
+        let n = samples.len() as f64;
+        let bin = |freq: f64| (freq * n / SAMPLE_RATE as f64).round() as usize;
+        // Counting the negative frequencies as well as the positive ones
+        2. * spectrum[bin(low)..bin(high)]
+            .iter()
+            .map(|x| x.norm_sqr())
+            .sum::<f64>()
+            / (n * n)
+    }
+
+    /// Frequency of a tone from the first to the last rising zero crossing, leaving out
+    /// the first and last tenth
+    fn frequency(samples: &[f32]) -> f64 {
+        let tenth = samples.len() / 10;
+        let crossings: Vec<f64> = samples[tenth..samples.len() - tenth]
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0. && pair[1] >= 0.)
+            .map(|(i, pair)| i as f64 + (pair[0] / (pair[0] - pair[1])) as f64)
+            .collect();
+
+        let cycles = (crossings.len() - 1) as f64;
+        cycles * SAMPLE_RATE as f64 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    #[test]
+    fn noise_at_snr() {
+        let sent = tone(1900., 4);
+        let power = 0.1_f64.powi(2) / 2.;
+
+        for snr_db in [0., 10., 20.] {
+            let options = ChannelOptions {
+                snr_db: Some(snr_db),
+                ..ChannelOptions::default()
+            };
+            let noise: Vec<f64> = simulate(&sent, &options)
+                .iter()
+                .zip(&sent)
+                .map(|(received, sent)| (received - sent) as f64)
+                .collect();
+
+            // The noise is white, so any band the width of the measurement bandwidth will do
+            let in_band = band_power(&noise, 500., 500. + SNR_BANDWIDTH);
+            let measured = 10. * (power / in_band).log10();
+            assert!(
+                (measured - snr_db).abs() < 0.3,
+                "noise for {snr_db}dB came out at {measured:.2}dB"
+            );
+        }
+    }
+
+    #[test]
+    fn freq_offset_moves_tone() {
+        let options = ChannelOptions {
+            freq_offset: 120.,
+            ..ChannelOptions::default()
+        };
+        let freq = frequency(&simulate(&tone(1900., 1), &options));
+        assert!((freq - 2020.).abs() < 0.1, "tone moved to {freq:.2}Hz");
+    }
+
+    #[test]
+    fn clock_skew_stretches_tone() {
+        // The receiver's clock running 1000ppm fast takes 1000ppm more samples of
+        // everything, so the tone reads 1000ppm lower
+        let options = ChannelOptions {
+            clock_ppm: 1000.,
+            ..ChannelOptions::default()
+        };
+        let sent = tone(1900., 2);
+        let received = simulate(&sent, &options);
+
+        let expected = sent.len() as f64 * 1.001;
+        assert!((received.len() as f64 - expected).abs() < 2.);
+        let freq = frequency(&received);
+        assert!(
+            (freq - 1900. / 1.001).abs() < 0.1,
+            "tone moved to {freq:.2}Hz"
+        );
+    }
+}
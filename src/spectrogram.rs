@@ -0,0 +1,365 @@
+use core::f64;
+
+use image::{Rgb, RgbImage};
+use num_complex::Complex32;
+use rustfft::FftPlanner;
+
+use crate::{
+    SAMPLE_RATE,
+    common::{DSPOut, DecoderOptions, get_calibration_header, us_to_n_samples},
+    dsp,
+};
+
+/// Frequencies marked along the top of the spectrogram: sync, black, the leader and white
+pub const MARKED_FREQS: [f64; 4] = [1200., 1500., 1900., 2300.];
+
+/// Colours of the lines marking each of `MARKED_FREQS`
+const MARKER_COLOURS: [Rgb<u8>; 4] = [
+    Rgb([0, 255, 255]),
+    Rgb([255, 0, 255]),
+    Rgb([0, 255, 0]),
+    Rgb([255, 255, 255]),
+];
+
+/// Width of the margin on the left holding the time and event labels
+const LEFT_MARGIN: u32 = 64;
+/// Height of the strip along the top holding the frequency labels
+const TOP_MARGIN: u32 = 16;
+
+/// How the spectrogram is drawn
+#[derive(Clone, Debug)]
+pub struct SpectrogramOptions {
+    /// Length of the window each row is worked out over in μs. Shorter shows syncs more
+    /// sharply, longer separates frequencies better
+    pub window_us: f64,
+    /// Time between rows in μs
+    pub step_us: f64,
+    /// Frequency at the left edge in Hz
+    pub min_freq: f64,
+    /// Frequency at the right edge in Hz
+    pub max_freq: f64,
+    /// Width of the waterfall in pixels, not counting the margin
+    pub width: u32,
+    /// Range of levels shown in dB, anything further below the loudest point is black
+    pub range_db: f64,
+}
+
+impl Default for SpectrogramOptions {
+    fn default() -> Self {
+        SpectrogramOptions {
+            window_us: 10_000.,
+            step_us: 10_000.,
+            min_freq: 500.,
+            max_freq: 3000.,
+            width: 800,
+            range_db: 60.,
+        }
+    }
+}
+
+/// Something found in a signal, annotated on the spectrogram
+#[derive(Clone, Copy, Debug)]
+pub enum Marker {
+    /// A calibration header
+    Header {
+        /// Sample the first leader tone starts at
+        start: usize,
+        /// Sample the VIS code starts at
+        vis_start: usize,
+        /// The VIS code
+        vis: u8,
+    },
+    /// A 1200Hz sync pulse starting at this sample
+    Sync(usize),
+}
+
+impl Marker {
+    /// Sample the marked event starts at
+    pub fn sample(&self) -> usize {
+        match self {
+            Marker::Header { start, .. } => *start,
+            Marker::Sync(sample) => *sample,
+        }
+    }
+}
+
+/// Look through `samples` for calibration headers and sync pulses, without decoding them.
+///
+/// Syncs after a header are looked for with the frequency offset measured from its leader.
+pub fn find_markers(samples: &[f32], options: &DecoderOptions) -> Vec<Marker> {
+    let track = dsp::demodulate(samples, options);
+    let mut out = DSPOut::new(&track);
+
+    // Every header, along with where it ends and the offset measured from it
+    let mut headers = Vec::new();
+    loop {
+        let before = out.get_pos();
+        out.set_freq_offset(0.);
+
+        match get_calibration_header(&mut out) {
+            Some(header) => headers.push((header, out.get_pos(), out.get_freq_offset())),
+            // Carry on past things that looked like a header but weren't, stopping once
+            // the samples run out
+            None if out.get_pos() > before => {}
+            None => break,
+        }
+    }
+
+    let mut markers = Vec::new();
+
+    // Syncs in any signal joined partway through, before the first header
+    let first_header = headers
+        .first()
+        .map_or(track.len(), |(header, ..)| header.start);
+    let mut syncs = DSPOut::new(&track).find_syncs(first_header, 1200., 2000., 15_000.);
+
+    for (i, (header, end, freq_offset)) in headers.iter().enumerate() {
+        markers.push(Marker::Header {
+            start: header.start,
+            vis_start: header.vis_start,
+            vis: header.vis,
+        });
+
+        let next = headers
+            .get(i + 1)
+            .map_or(track.len(), |(next, ..)| next.start);
+        let mut out = DSPOut::new(&track);
+        out.set_to(*end);
+        out.set_freq_offset(*freq_offset);
+        syncs.extend(out.find_syncs(next, 1200., 2000., 15_000.));
+    }
+
+    markers.extend(syncs.iter().map(|sync| Marker::Sync(sync.start as usize)));
+    markers.sort_by_key(Marker::sample);
+    markers
+}
+
+/// Render a waterfall of `samples`, time going down and frequency across, with
+/// `MARKED_FREQS` and `markers` drawn over it.
+pub fn render(samples: &[f32], markers: &[Marker], options: &SpectrogramOptions) -> RgbImage {
+    let window_len = us_to_n_samples(options.window_us).max(2);
+    let step = us_to_n_samples(options.step_us).max(1);
+    // Zero pad the window so the bins are close enough together to interpolate between
+    let fft_len = (window_len * 4).next_power_of_two();
+
+    let fft = FftPlanner::new().plan_fft_forward(fft_len);
+    let window: Vec<f32> = (0..window_len)
+        .map(|i| (0.5 - 0.5 * (f64::consts::TAU * i as f64 / (window_len - 1) as f64).cos()) as f32)
+        .collect();
+
+    let bin_hz = SAMPLE_RATE as f64 / fft_len as f64;
+    let column_freq = |x: u32| {
+        options.min_freq
+            + (options.max_freq - options.min_freq) * (x as f64 + 0.5) / options.width as f64
+    };
+
+    // Power in dB of every pixel of the waterfall
+    let rows = samples.len() / step;
+    let mut levels = Vec::with_capacity(rows * options.width as usize);
+    let mut buffer = vec![Complex32::ZERO; fft_len];
+    for row in 0..rows {
+        // Centre the window on the row's time
+        let centre = row * step;
+        for (i, bin) in buffer.iter_mut().enumerate() {
+            let sample = (centre + i)
+                .checked_sub(window_len / 2)
+                .filter(|_| i < window_len)
+                .and_then(|at| samples.get(at));
+            *bin = Complex32::new(sample.map_or(0., |sample| sample * window[i]), 0.);
+        }
+        fft.process(&mut buffer);
+
+        for x in 0..options.width {
+            let bin = column_freq(x) / bin_hz;
+            let (below, frac) = ((bin as usize).min(fft_len / 2 - 1), bin.fract() as f32);
+            let power =
+                buffer[below].norm_sqr() * (1. - frac) + buffer[below + 1].norm_sqr() * frac;
+            levels.push(10. * (power as f64).max(1e-20).log10());
+        }
+    }
+
+    let loudest = levels.iter().copied().fold(f64::MIN, f64::max);
+    let mut image = RgbImage::new(LEFT_MARGIN + options.width, TOP_MARGIN + rows as u32);
+
+    for (i, level) in levels.iter().enumerate() {
+        let (x, y) = (i as u32 % options.width, i as u32 / options.width);
+        let value = 1. - ((loudest - level) / options.range_db).clamp(0., 1.);
+        image.put_pixel(LEFT_MARGIN + x, TOP_MARGIN + y, heat(value));
+    }
+
+    // Frequency markers, labelled along the top and dotted down the waterfall
+    for (freq, colour) in MARKED_FREQS.iter().zip(MARKER_COLOURS) {
+        if *freq < options.min_freq || *freq >= options.max_freq {
+            continue;
+        }
+        let x = LEFT_MARGIN
+            + ((freq - options.min_freq) / (options.max_freq - options.min_freq)
+                * options.width as f64) as u32;
+
+        draw_text(
+            &mut image,
+            &format!("{freq}"),
+            x.saturating_sub(15),
+            2,
+            colour,
+        );
+        for y in (TOP_MARGIN - 3..image.height()).step_by(4) {
+            image.put_pixel(x, y, colour);
+        }
+    }
+
+    // Time down the side every 10 seconds
+    let row_of = |sample: usize| TOP_MARGIN + (sample / step) as u32;
+    for secs in (0..).step_by(10) {
+        let y = row_of(secs * SAMPLE_RATE);
+        if y >= image.height() {
+            break;
+        }
+        draw_text(&mut image, &format!("{secs}S"), 2, y, Rgb([200, 200, 200]));
+    }
+
+    for marker in markers {
+        match *marker {
+            Marker::Header {
+                start,
+                vis_start,
+                vis,
+            } => {
+                for (sample, colour) in [(start, Rgb([0, 255, 0])), (vis_start, Rgb([255, 255, 0]))]
+                {
+                    let y = row_of(sample);
+                    if y < image.height() {
+                        for x in LEFT_MARGIN..image.width() {
+                            image.put_pixel(x, y, colour);
+                        }
+                    }
+                }
+                draw_text(
+                    &mut image,
+                    &format!("VIS {vis}"),
+                    2,
+                    row_of(vis_start) + 2,
+                    Rgb([255, 255, 0]),
+                );
+            }
+            Marker::Sync(sample) => {
+                let y = row_of(sample);
+                if y < image.height() {
+                    for x in LEFT_MARGIN - 8..LEFT_MARGIN {
+                        image.put_pixel(x, y, Rgb([0, 255, 255]));
+                    }
+                }
+            }
+        }
+    }
+
+    image
+}
+
+/// Map a level between 0 and 1 to a colour, black through blue, red and yellow to white
+fn heat(value: f64) -> Rgb<u8> {
+    const STOPS: [[f64; 3]; 5] = [
+        [0., 0., 0.],
+        [0., 0., 160.],
+        [200., 0., 0.],
+        [255., 220., 0.],
+        [255., 255., 255.],
+    ];
+
+    let at = value * (STOPS.len() - 1) as f64;
+    let (i, frac) = (
+        (at as usize).min(STOPS.len() - 2),
+        at - (at as usize).min(STOPS.len() - 2) as f64,
+    );
+    let mix = |c: usize| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * frac) as u8;
+    Rgb([mix(0), mix(1), mix(2)])
+}
+
+/// Draw `text` with its top left corner at `x`, `y`, in a 3x5 pixel font doubled in size.
+/// Only digits and the few letters the labels use are drawn, anything else is left as a gap.
+pub(crate) fn draw_text(image: &mut RgbImage, text: &str, x: u32, y: u32, colour: Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let Some(glyph) = glyph(c) else {
+            continue;
+        };
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (4 >> col) == 0 {
+                    continue;
+                }
+
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let px = x + i as u32 * 8 + col * 2 + dx;
+                    let py = y + row as u32 * 2 + dy;
+                    if px < image.width() && py < image.height() {
+                        image.put_pixel(px, py, colour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a character in the 3x5 font, top first, the highest of the 3 bits on the left
+fn glyph(c: char) -> Option<[u8; 5]> {
+    Some(match c {
+        '0' => [7, 5, 5, 5, 7],
+        '1' => [2, 6, 2, 2, 7],
+        '2' => [7, 1, 7, 4, 7],
+        '3' => [7, 1, 7, 1, 7],
+        '4' => [5, 5, 7, 1, 1],
+        '5' => [7, 4, 7, 1, 7],
+        '6' => [7, 4, 7, 5, 7],
+        '7' => [7, 1, 1, 1, 1],
+        '8' => [7, 5, 7, 5, 7],
+        '9' => [7, 5, 7, 1, 7],
+        'I' => [7, 2, 2, 2, 7],
+        'S' => [3, 4, 2, 1, 6],
+        'V' => [5, 5, 5, 5, 2],
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{EncoderOptions, Mode, Signal, us_to_samples};
+
+    #[test]
+    fn markers_at_header_and_syncs() {
+        // A Martin M1 header, then syncs between stretches of black 100ms apart
+        let mut signal = Signal::new();
+        signal.push_calibration_header(Mode::MartinM1, &EncoderOptions::default());
+        for _ in 0..5 {
+            signal.push(1200, 4862.);
+            signal.push(1500, 95_138.);
+        }
+
+        let markers = find_markers(&signal.to_samples(), &DecoderOptions::default());
+        // Anything found should be within a millisecond of where it was sent
+        let near = |sample: usize, us: f64| (sample as f64 - us_to_samples(us)).abs() < 44.1;
+
+        let [header, syncs @ ..] = &markers[..] else {
+            panic!("no markers found");
+        };
+        // The first leader comes after the 200ms preamble, and the VIS code after both
+        // leaders and the break between them
+        assert!(matches!(
+            *header,
+            Marker::Header { start, vis_start, vis: 44 }
+                if near(start, 200_000.) && near(vis_start, 810_000.)
+        ));
+
+        // The header ends with the VIS code's stop bit 300ms after it starts
+        assert_eq!(syncs.len(), 5);
+        for (i, sync) in syncs.iter().enumerate() {
+            let at = 1_110_000. + i as f64 * 100_000.;
+            assert!(
+                matches!(*sync, Marker::Sync(sample) if near(sample, at)),
+                "sync {i} found at {}, sent at {at}μs",
+                sync.sample()
+            );
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::io::{self, Write};
+
+use image::{Rgb, RgbImage};
+
+use crate::{
+    common::{CalibrationHeader, SyncPulse},
+    spectrogram::draw_text,
+};
+
+/// Lowest frequency shown on plots in Hz
+const PLOT_MIN_FREQ: f64 = 1000.;
+/// Highest frequency shown on plots in Hz
+const PLOT_MAX_FREQ: f64 = 2600.;
+/// Frequencies with grid lines on plots: sync, black, the leader and white
+const PLOT_GRID: [f64; 4] = [1200., 1500., 1900., 2300.];
+
+/// Width of the margin on the left of plots holding the frequency labels
+const LEFT_MARGIN: u32 = 40;
+/// Height of the strip along the bottom of plots showing the pixel windows
+const PIXEL_STRIP: u32 = 8;
+
+/// Something read by a decoder, recorded when `DecoderOptions::trace` is set. Positions
+/// are in samples and frequencies are corrected for the frequency offset.
+#[derive(Clone, Copy, Debug)]
+pub enum TraceEvent {
+    /// A calibration header, ending at `end`, with the frequency offset measured from it
+    Header {
+        header: CalibrationHeader,
+        end: f64,
+        freq_offset: f64,
+    },
+    /// A bit of the VIS code and the average frequency read over it
+    VisBit {
+        kind: VisBit,
+        start: f64,
+        end: f64,
+        freq: f64,
+    },
+    /// A line's sync pulse
+    Sync(SyncPulse),
+    /// The window a pixel was read from and the average frequency over it
+    Pixel { start: f64, end: f64, freq: f64 },
+}
+
+/// Which bit of the VIS code a `TraceEvent::VisBit` is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VisBit {
+    Start,
+    /// One of the 7 data bits, counting from the least significant
+    Data(u8),
+    Parity,
+    Stop,
+}
+
+impl TraceEvent {
+    /// Sample the event starts at
+    pub fn start(&self) -> f64 {
+        match self {
+            TraceEvent::Header { header, .. } => header.start as f64,
+            TraceEvent::VisBit { start, .. } | TraceEvent::Pixel { start, .. } => *start,
+            TraceEvent::Sync(sync) => sync.start,
+        }
+    }
+
+    /// Sample the event ends at
+    pub fn end(&self) -> f64 {
+        match self {
+            TraceEvent::Header { end, .. }
+            | TraceEvent::VisBit { end, .. }
+            | TraceEvent::Pixel { end, .. } => *end,
+            TraceEvent::Sync(sync) => sync.end,
+        }
+    }
+}
+
+/// Write the frequency track out as CSV, a row of `sample,freq` for every sample
+pub fn write_track_csv<W: Write>(mut writer: W, track: &[f64]) -> io::Result<()> {
+    writeln!(writer, "sample,freq")?;
+    for (i, freq) in track.iter().enumerate() {
+        writeln!(writer, "{i},{freq:.1}")?;
+    }
+    writer.flush()
+}
+
+/// Write `events` out as CSV, with a row of `event,start,end,freq,detail` for each in the
+/// order they start. `freq` is empty for events that don't have one.
+pub fn write_events_csv<W: Write>(mut writer: W, events: &[TraceEvent]) -> io::Result<()> {
+    let mut events = events.to_vec();
+    events.sort_by(|a, b| a.start().total_cmp(&b.start()));
+
+    writeln!(writer, "event,start,end,freq,detail")?;
+    for event in &events {
+        let (start, end) = (event.start(), event.end());
+
+        match event {
+            TraceEvent::Header {
+                header,
+                freq_offset,
+                ..
+            } => writeln!(
+                writer,
+                "header,{start:.2},{end:.2},,vis={};offset={freq_offset:.1}",
+                header.vis
+            )?,
+            TraceEvent::VisBit { kind, freq, .. } => {
+                let kind = match kind {
+                    VisBit::Start => "start".to_string(),
+                    VisBit::Data(bit) => format!("data{bit}"),
+                    VisBit::Parity => "parity".to_string(),
+                    VisBit::Stop => "stop".to_string(),
+                };
+                writeln!(writer, "vis_bit,{start:.2},{end:.2},{freq:.1},{kind}")?
+            }
+            TraceEvent::Sync(sync) => {
+                let clean = if sync.clean { "clean" } else { "guessed" };
+                writeln!(writer, "sync,{start:.2},{end:.2},,{clean}")?
+            }
+            TraceEvent::Pixel { freq, .. } => {
+                writeln!(writer, "pixel,{start:.2},{end:.2},{freq:.1},")?
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Plot `track[from..to]` with `events` drawn behind it, `width` by `height` pixels not
+/// counting the margins.
+///
+/// The track is corrected by the frequency offset measured from the header before it, so
+/// it lines up with what the decoder read. The header, VIS bits and syncs are shaded in,
+/// with the frequency read for each VIS bit and pixel drawn as a white line over its
+/// window. Pixel windows are marked in alternating colours along the bottom so where they
+/// fall is clear when zoomed in.
+pub fn render_plot(
+    track: &[f64],
+    events: &[TraceEvent],
+    from: usize,
+    to: usize,
+    width: u32,
+    height: u32,
+) -> RgbImage {
+    let to = to.min(track.len());
+    let from = from.min(to);
+    let span = (to - from).max(1) as f64;
+
+    let mut image = RgbImage::new(LEFT_MARGIN + width, height + PIXEL_STRIP);
+
+    let x_of = |sample: f64| LEFT_MARGIN as f64 + (sample - from as f64) / span * width as f64;
+    let y_of = |freq: f64| {
+        let frac = (freq - PLOT_MIN_FREQ) / (PLOT_MAX_FREQ - PLOT_MIN_FREQ);
+        ((1. - frac.clamp(0., 1.)) * (height - 1) as f64) as u32
+    };
+    // Columns from `start` to `end` that are on the plot
+    let columns = |start: f64, end: f64| {
+        let left = x_of(start).max(LEFT_MARGIN as f64) as u32;
+        let right = (x_of(end).ceil() as u32).min(LEFT_MARGIN + width);
+        left..right.max(left)
+    };
+
+    let visible: Vec<&TraceEvent> = events
+        .iter()
+        .filter(|event| event.end() > from as f64 && event.start() < to as f64)
+        .collect();
+
+    // Shade the events in behind the track
+    for event in &visible {
+        let shade = match event {
+            TraceEvent::Header { .. } => Rgb([0, 40, 0]),
+            TraceEvent::VisBit { .. } => Rgb([60, 60, 0]),
+            TraceEvent::Sync(sync) if sync.clean => Rgb([0, 60, 80]),
+            TraceEvent::Sync(_) => Rgb([90, 0, 0]),
+            TraceEvent::Pixel { .. } => continue,
+        };
+
+        for x in columns(event.start(), event.end()) {
+            for y in 0..height {
+                // Let VIS bits show over the header they're part of
+                let current = *image.get_pixel(x, y);
+                if current == Rgb([0, 0, 0]) || matches!(event, TraceEvent::VisBit { .. }) {
+                    image.put_pixel(x, y, shade);
+                }
+            }
+        }
+    }
+
+    // Frequency grid
+    for freq in PLOT_GRID {
+        let y = y_of(freq);
+        for x in (LEFT_MARGIN..LEFT_MARGIN + width).step_by(3) {
+            image.put_pixel(x, y, Rgb([90, 90, 90]));
+        }
+        draw_text(
+            &mut image,
+            &format!("{freq}"),
+            2,
+            y.saturating_sub(5),
+            Rgb([200, 200, 200]),
+        );
+    }
+
+    // Headers and the offset measured from each, for correcting the track like the decoder
+    let mut headers: Vec<(f64, f64)> = events
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Header { freq_offset, .. } => Some((event.start(), *freq_offset)),
+            _ => None,
+        })
+        .collect();
+    headers.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // The track, as the range it covers in each column
+    for x in 0..width {
+        let start = from + (x as f64 / width as f64 * span) as usize;
+        let end = (from + ((x + 1) as f64 / width as f64 * span).ceil() as usize)
+            .min(to)
+            .max(start + 1);
+        let Some(samples) = track.get(start..end) else {
+            continue;
+        };
+
+        let offset = headers
+            .iter()
+            .rev()
+            .find(|(header_start, _)| *header_start <= start as f64)
+            .map_or(0., |(_, offset)| *offset);
+
+        let (low, high) = samples
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(low, high), freq| {
+                (low.min(freq - offset), high.max(freq - offset))
+            });
+        for y in y_of(high)..=y_of(low) {
+            image.put_pixel(LEFT_MARGIN + x, y, Rgb([255, 200, 0]));
+        }
+    }
+
+    // What the decoder read, over the track
+    for (i, event) in visible.iter().enumerate() {
+        let freq = match event {
+            TraceEvent::VisBit { freq, .. } | TraceEvent::Pixel { freq, .. } => *freq,
+            _ => continue,
+        };
+
+        let y = y_of(freq);
+        for x in columns(event.start(), event.end()) {
+            image.put_pixel(x, y, Rgb([255, 255, 255]));
+
+            if matches!(event, TraceEvent::Pixel { .. }) {
+                let colour = if i % 2 == 0 {
+                    Rgb([0, 160, 255])
+                } else {
+                    Rgb([255, 80, 160])
+                };
+                for y in height..height + PIXEL_STRIP {
+                    image.put_pixel(x, y, colour);
+                }
+            }
+        }
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_csv() {
+        let mut csv = Vec::new();
+        write_track_csv(&mut csv, &[1200., 1500., 1900.]).unwrap();
+
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines, ["sample,freq", "0,1200.0", "1,1500.0", "2,1900.0"]);
+    }
+
+    #[test]
+    fn events_csv_in_order() {
+        let events = [
+            TraceEvent::Pixel {
+                start: 20.,
+                end: 30.,
+                freq: 1900.,
+            },
+            TraceEvent::VisBit {
+                kind: VisBit::Start,
+                start: 0.,
+                end: 10.,
+                freq: 1200.,
+            },
+        ];
+        let mut csv = Vec::new();
+        write_events_csv(&mut csv, &events).unwrap();
+
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), events.len() + 1);
+        assert_eq!(lines[0], "event,start,end,freq,detail");
+        assert!(lines[1].starts_with("vis_bit,0.00,10.00,1200.0,"));
+        assert_eq!(lines[2], "pixel,20.00,30.00,1900.0,");
+    }
+}
@@ -0,0 +1,155 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+use crate::{
+    common::{DSPOut, DecoderOptions, Mode, us_to_samples},
+    dsp,
+};
+
+/// How a signal is cut up into lines by `decode_unsynced`.
+///
+/// The syncs aren't looked at, every line is just the next `line_us` of the signal. When the
+/// timing is off the image comes out slanted and shifted sideways, which `slant_ppm` and
+/// `offset` undo by hand.
+#[derive(Clone, Debug)]
+pub struct UnsyncedOptions {
+    /// Length of each line in μs
+    pub line_us: f64,
+    /// Pixels across each line when showing it in greyscale
+    pub width: u32,
+    /// Sample the first line starts at. Moving it shifts the image sideways, lining the
+    /// syncs up with the left edge
+    pub offset: f64,
+    /// How much longer lines really are than `line_us` in parts per million, from the
+    /// sample clocks not matching. Getting this right straightens a slanted image
+    pub slant_ppm: f64,
+    /// How far the signal is mistuned in Hz, subtracted from every reading
+    pub freq_offset: f64,
+    /// Put the pixels into colour channels where this mode sends them. Otherwise every line
+    /// is shown whole in greyscale, syncs included, so they show up as a dark stripe
+    pub colour: Option<Mode>,
+}
+
+impl UnsyncedOptions {
+    /// Options cutting the signal into lines of `mode`, shown in greyscale with pixels
+    /// the mode's size
+    pub fn for_mode(mode: Mode) -> UnsyncedOptions {
+        let pixel_us = mode.scans()[0].pixel_us;
+
+        UnsyncedOptions {
+            line_us: mode.line_us(),
+            width: (mode.line_us() / pixel_us).round() as u32,
+            offset: 0.,
+            slant_ppm: 0.,
+            freq_offset: 0.,
+            colour: None,
+        }
+    }
+
+    /// How much the slant stretches every length by
+    fn stretch(&self) -> f64 {
+        1. + self.slant_ppm * 1e-6
+    }
+
+    /// Sample line `line` starts at, after the offset and slant
+    pub fn line_start(&self, line: usize) -> f64 {
+        self.offset + line as f64 * us_to_samples(self.line_us) * self.stretch()
+    }
+}
+
+/// Demodulate `samples` and cut them up into lines as described by `options`, for getting
+/// something out of signals the normal decoders can't sync to
+pub fn decode_unsynced(
+    samples: &[f32],
+    options: &UnsyncedOptions,
+    decoder: &DecoderOptions,
+) -> DynamicImage {
+    let track = dsp::demodulate(samples, decoder);
+    render(&track, options)
+}
+
+/// Cut a frequency track from `dsp::demodulate` up into lines as described by `options`.
+/// Rendering again with different options is cheap, so they can be tweaked until the
+/// image comes out straight.
+pub fn render(track: &[f64], options: &UnsyncedOptions) -> DynamicImage {
+    let mut out = DSPOut::new(track);
+    out.set_freq_offset(options.freq_offset);
+
+    let line_len = us_to_samples(options.line_us) * options.stretch();
+    let lines = if line_len > 0. {
+        ((track.len() as f64 - options.offset.max(0.)) / line_len).max(0.) as u32
+    } else {
+        0
+    };
+
+    let width = match options.colour {
+        Some(mode) => mode
+            .scans()
+            .iter()
+            .map(|scan| scan.pixels)
+            .max()
+            .unwrap_or(0),
+        None => options.width,
+    };
+    let mut image = RgbImage::new(width, lines);
+
+    for line in 0..lines {
+        let start = options.line_start(line as usize);
+        // The first line can start before the track does when the offset is negative
+        if start < 0. {
+            continue;
+        }
+
+        match options.colour {
+            Some(mode) => {
+                for scan in mode.scans() {
+                    out.set_to(start + us_to_samples(scan.start_us) * options.stretch());
+                    let Some(values) =
+                        out.take_scanline(scan.pixels as usize, scan.pixel_us * options.stretch())
+                    else {
+                        continue;
+                    };
+
+                    for (x, value) in values.into_iter().enumerate() {
+                        let pixel = image.get_pixel_mut(x as u32, line);
+                        match scan.channel {
+                            Some(channel) => pixel.0[channel] = value,
+                            None => *pixel = Rgb([value; 3]),
+                        }
+                    }
+                }
+            }
+            None => {
+                out.set_to(start);
+                let pixel_us = options.line_us * options.stretch() / options.width as f64;
+                let Some(values) = out.take_scanline(options.width as usize, pixel_us) else {
+                    continue;
+                };
+
+                for (x, value) in values.into_iter().enumerate() {
+                    image.put_pixel(x as u32, line, Rgb([value; 3]));
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_size() {
+        // Ten and a half Martin M1 lines, the last half not making a line of its own
+        let mut options = UnsyncedOptions::for_mode(Mode::MartinM1);
+        let track = vec![1900.; (us_to_samples(options.line_us) * 10.5) as usize];
+
+        let image = render(&track, &options);
+        assert_eq!((image.width(), image.height()), (options.width, 10));
+
+        options.colour = Some(Mode::MartinM1);
+        let image = render(&track, &options);
+        assert_eq!((image.width(), image.height()), (320, 10));
+    }
+}
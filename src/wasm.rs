@@ -29,7 +29,7 @@ impl SSTVDecoderWASM {
         let result = self.inner.decode(&buf);
 
         match result {
-            DecodeResult::Finished(image) | DecodeResult::Partial(image) => {
+            DecodeResult::Finished(image, _) | DecodeResult::Partial(image, _) => {
                 Some(image.as_bytes().to_vec())
             }
             DecodeResult::NoneFound => None,
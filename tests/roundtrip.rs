@@ -0,0 +1,366 @@
+//! Round trip tests, encoding a test card with each mode, passing it through the channel
+//! simulator and decoding it again. The decoded image has to score above a PSNR and SSIM
+//! threshold against what was sent, so changes to the decoders can't quietly make them
+//! worse. The clean thresholds sit just under what the decoders manage at the moment, so
+//! any loss on an unimpaired signal shows up, while the impaired ones leave a couple of dB.
+
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use rsstv::{
+    common::{DecodeResult, DecoderOptions, DetectingDecoder, Mode, us_to_samples},
+    dsp::DemodulatorKind,
+    sim::{self, ChannelOptions, Fading, Tone},
+};
+
+/// A test card with smooth gradients for checking tones come out right, and hard edged
+/// bars and a checkerboard for checking timing
+fn test_card(width: u32, height: u32) -> DynamicImage {
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+
+        if v < 0.25 {
+            // Colour bars
+            let bar = (u * 8.) as u8;
+            let on = |bit: u8| if bar & bit != 0 { 255 } else { 0 };
+            Rgb([on(4), on(2), on(1)])
+        } else if v < 0.5 {
+            // Checkerboard, 16 pixels across
+            let value = if (x / 16 + y / 16) % 2 == 0 { 230 } else { 25 };
+            Rgb([value; 3])
+        } else {
+            // Gradients of each channel
+            Rgb([
+                (u * 255.) as u8,
+                ((v - 0.5) * 2. * 255.) as u8,
+                ((1. - u) * 255.) as u8,
+            ])
+        }
+    });
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Size of the images each mode sends
+fn dimensions(mode: Mode) -> (u32, u32) {
+    match mode {
+        Mode::MartinM1 => (320, 256),
+        Mode::Fax480 => (512, 480),
+    }
+}
+
+/// Encode the test card with `mode` and pass it through the channel, returning the image
+/// sent and the samples received
+fn transmit(mode: Mode, channel: &ChannelOptions) -> (DynamicImage, Vec<f32>) {
+    let (width, height) = dimensions(mode);
+    let card = test_card(width, height);
+    // FAX480 only sends luminance, so that's all it can be expected to get back
+    let sent = match mode {
+        Mode::MartinM1 => card,
+        Mode::Fax480 => DynamicImage::ImageLuma8(card.to_luma8()),
+    };
+
+    let samples = mode.transcoder().encode(sent.clone()).to_samples();
+    (sent, sim::simulate(&samples, channel))
+}
+
+/// Encode the test card with `mode`, pass it through the channel and decode it, returning
+/// the image sent and the one received
+fn round_trip(
+    mode: Mode,
+    channel: &ChannelOptions,
+    options: DecoderOptions,
+) -> (DynamicImage, DynamicImage) {
+    let (sent, received) = transmit(mode, channel);
+
+    match mode.decoder(options).decode(&received) {
+        DecodeResult::Finished(image, _) => (sent, image),
+        DecodeResult::Partial(..) => panic!("{mode:?} decode didn't finish"),
+        DecodeResult::NoneFound => panic!("no {mode:?} image found"),
+    }
+}
+
+/// Peak signal to noise ratio between two images in dB, over every colour channel
+fn psnr(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    assert_eq!(a.dimensions(), b.dimensions());
+
+    let mse = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum::<f64>()
+        / a.as_raw().len() as f64;
+
+    10. * (255_f64.powi(2) / mse.max(f64::MIN_POSITIVE)).log10()
+}
+
+/// Mean structural similarity between the luminance of two images, over 8x8 windows
+/// stepped 4 pixels at a time
+fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const STEP: u32 = 4;
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+    let (a, b): (GrayImage, GrayImage) = (a.to_luma8(), b.to_luma8());
+    assert_eq!(a.dimensions(), b.dimensions());
+    let (width, height) = a.dimensions();
+
+    let mut total = 0.;
+    let mut windows = 0;
+    for y in (0..=height - WINDOW).step_by(STEP as usize) {
+        for x in (0..=width - WINDOW).step_by(STEP as usize) {
+            let pixels: Vec<(f64, f64)> = (y..y + WINDOW)
+                .flat_map(|y| (x..x + WINDOW).map(move |x| (x, y)))
+                .map(|(x, y)| (a.get_pixel(x, y).0[0] as f64, b.get_pixel(x, y).0[0] as f64))
+                .collect();
+            let n = pixels.len() as f64;
+
+            let mean_a = pixels.iter().map(|(a, _)| a).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|(_, b)| b).sum::<f64>() / n;
+            let var_a = pixels
+                .iter()
+                .map(|(a, _)| (a - mean_a).powi(2))
+                .sum::<f64>()
+                / n;
+            let var_b = pixels
+                .iter()
+                .map(|(_, b)| (b - mean_b).powi(2))
+                .sum::<f64>()
+                / n;
+            let covariance = pixels
+                .iter()
+                .map(|(a, b)| (a - mean_a) * (b - mean_b))
+                .sum::<f64>()
+                / n;
+
+            total += ((2. * mean_a * mean_b + C1) * (2. * covariance + C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+/// Round trip `mode` through `channel`, checking the result scores at least `min_psnr`
+/// and `min_ssim`
+fn check(
+    mode: Mode,
+    channel: ChannelOptions,
+    options: DecoderOptions,
+    min_psnr: f64,
+    min_ssim: f64,
+) {
+    let (sent, received) = round_trip(mode, &channel, options);
+    let (psnr, ssim) = (psnr(&sent, &received), ssim(&sent, &received));
+    println!("{mode:?}: PSNR {psnr:.2}dB, SSIM {ssim:.3}");
+
+    assert!(psnr >= min_psnr, "PSNR {psnr:.2}dB below {min_psnr}dB");
+    assert!(ssim >= min_ssim, "SSIM {ssim:.3} below {min_ssim}");
+}
+
+#[test]
+fn martin_m1_clean() {
+    check(
+        Mode::MartinM1,
+        ChannelOptions::default(),
+        DecoderOptions::default(),
+        22.5,
+        0.875,
+    );
+}
+
+#[test]
+fn fax480_clean() {
+    check(
+        Mode::Fax480,
+        ChannelOptions::default(),
+        DecoderOptions::default(),
+        25.5,
+        0.895,
+    );
+}
+
+#[test]
+fn martin_m1_noise() {
+    let channel = ChannelOptions {
+        snr_db: Some(15.),
+        ..ChannelOptions::default()
+    };
+    check(
+        Mode::MartinM1,
+        channel,
+        DecoderOptions::default(),
+        20.,
+        0.63,
+    );
+}
+
+#[test]
+fn fax480_noise() {
+    let channel = ChannelOptions {
+        snr_db: Some(15.),
+        ..ChannelOptions::default()
+    };
+    check(Mode::Fax480, channel, DecoderOptions::default(), 22.5, 0.5);
+}
+
+#[test]
+fn martin_m1_noise_pll() {
+    let channel = ChannelOptions {
+        snr_db: Some(8.),
+        ..ChannelOptions::default()
+    };
+    let options = DecoderOptions {
+        demodulator: DemodulatorKind::Pll,
+        ..DecoderOptions::default()
+    };
+    check(Mode::MartinM1, channel, options, 18.5, 0.62);
+}
+
+#[test]
+fn martin_m1_fading() {
+    let channel = ChannelOptions {
+        snr_db: Some(25.),
+        paths: Fading::Good.paths().to_vec(),
+        ..ChannelOptions::default()
+    };
+    check(Mode::MartinM1, channel, DecoderOptions::default(), 19., 0.7);
+}
+
+#[test]
+fn martin_m1_mistuned_and_skewed() {
+    let channel = ChannelOptions {
+        freq_offset: 120.,
+        clock_ppm: 200.,
+        ..ChannelOptions::default()
+    };
+    check(
+        Mode::MartinM1,
+        channel,
+        DecoderOptions::default(),
+        23.5,
+        0.85,
+    );
+}
+
+#[test]
+fn martin_m1_interference() {
+    let channel = ChannelOptions {
+        snr_db: Some(25.),
+        tones: vec![Tone {
+            freq: 1750.,
+            level_db: -15.,
+        }],
+        ssb_filter: true,
+        ..ChannelOptions::default()
+    };
+    check(
+        Mode::MartinM1,
+        channel,
+        DecoderOptions::default(),
+        19.5,
+        0.57,
+    );
+}
+
+/// Length of the Martin M1 calibration header in μs, including the encoder's preamble
+const M1_HEADER_US: f64 = 1_110_000.;
+
+/// Send the test card with Martin M1 over a clean channel and decode it without the header,
+/// starting from `cut_us` into the signal. `rows` have to come back, as the top of the
+/// image, scoring at least `min_psnr` against the rows sent from `first_row` on
+fn check_headerless(cut_us: f64, first_row: u32, rows: u32, min_psnr: f64) {
+    let (sent, samples) = transmit(Mode::MartinM1, &ChannelOptions::default());
+    let options = DecoderOptions {
+        headerless: true,
+        ..DecoderOptions::default()
+    };
+
+    let received = &samples[us_to_samples(cut_us) as usize..];
+    let (image, info) = match Mode::MartinM1.decoder(options).decode(received) {
+        DecodeResult::Finished(image, info) | DecodeResult::Partial(image, info) => (image, info),
+        DecodeResult::NoneFound => panic!("no line syncs found"),
+    };
+    assert_eq!(info.line_quality.len(), rows as usize);
+
+    let sent = sent.crop_imm(0, first_row, sent.width(), rows);
+    let psnr = psnr(&sent, &image.crop_imm(0, 0, image.width(), rows));
+    println!(
+        "MartinM1 from {:.2}s: PSNR {psnr:.2}dB over {rows} rows",
+        cut_us / 1e6
+    );
+    assert!(psnr >= min_psnr, "PSNR {psnr:.2}dB below {min_psnr}dB");
+}
+
+#[test]
+fn martin_m1_header_cut_off() {
+    // Joined straight after the header, so every line is still there
+    check_headerless(M1_HEADER_US, 0, 256, 22.);
+}
+
+#[test]
+fn martin_m1_joined_partway() {
+    // Joined partway through row 100, so the first whole line is row 101
+    let cut = M1_HEADER_US + 100.3 * Mode::MartinM1.line_us();
+    check_headerless(cut, 101, 155, 25.5);
+}
+
+#[test]
+fn snr_estimate_tracks_channel() {
+    // The decoder measures over the syncs in its own passband rather than the channel's
+    // 3KHz, so the estimate only has to come out close
+    let estimate = |snr_db: f64| {
+        let channel = ChannelOptions {
+            snr_db: Some(snr_db),
+            ..ChannelOptions::default()
+        };
+        let (_, received) = transmit(Mode::MartinM1, &channel);
+
+        match Mode::MartinM1.transcoder().decode(&received) {
+            DecodeResult::Finished(_, info) => info.snr_db,
+            _ => panic!("decode at {snr_db}dB didn't finish"),
+        }
+    };
+
+    let (low, high) = (estimate(10.), estimate(25.));
+    println!("SNR estimated as {low:.2}dB at 10dB, {high:.2}dB at 25dB");
+
+    assert!(low < high, "{low:.2}dB at 10dB, {high:.2}dB at 25dB");
+    assert!((low - 10.).abs() < 2., "{low:.2}dB at 10dB");
+    assert!((high - 25.).abs() < 2., "{high:.2}dB at 25dB");
+}
+
+#[test]
+fn fax480_detected_live() {
+    // FAX480 sends 450ms of start tones and 20 phasing lines between its header and image,
+    // and the signal is joined partway through row 300
+    let line_us = Mode::Fax480.line_us();
+    let cut = M1_HEADER_US + 450_560. + (20. + 300.3) * line_us;
+    let (sent, samples) = transmit(Mode::Fax480, &ChannelOptions::default());
+
+    // Fed in chunks like the CLI does when decoding live, without saying which mode it is
+    let options = DecoderOptions {
+        headerless: true,
+        ..DecoderOptions::default()
+    };
+    let mut decoder = DetectingDecoder::new(None, options);
+    let mut result = DecodeResult::NoneFound;
+    for chunk in samples[us_to_samples(cut) as usize..].chunks(100_000) {
+        result = decoder.decode(chunk);
+    }
+    assert_eq!(decoder.mode(), Some(Mode::Fax480));
+
+    let (image, info) = match result {
+        DecodeResult::Finished(image, info) | DecodeResult::Partial(image, info) => (image, info),
+        DecodeResult::NoneFound => panic!("no FAX480 line syncs found"),
+    };
+    let rows = 480 - 301;
+    assert_eq!(info.line_quality.len(), rows as usize);
+
+    let sent = sent.crop_imm(0, 301, sent.width(), rows);
+    let psnr = psnr(&sent, &image.crop_imm(0, 0, image.width(), rows));
+    println!("FAX480 detected live: PSNR {psnr:.2}dB over {rows} rows");
+    assert!(psnr >= 35.5, "PSNR {psnr:.2}dB below 35.5dB");
+}